@@ -100,7 +100,7 @@ impl Context {
                                 .into_iter()
                                 .chain(decl.nodes.1.iter().flat_map(|n| n.into_iter()))
                                 .chain(decl.nodes.2.iter().flat_map(|n| n.into_iter()))
-                                .chain(decl.nodes.3.into_iter())
+                                .chain(&decl.nodes.3)
                                 .chain(decl.nodes.4.iter().flat_map(|n| n.into_iter())),
                             decl_assign,
                         ));
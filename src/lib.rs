@@ -9,10 +9,14 @@
 extern crate log;
 
 use anyhow::{anyhow, Context as _, Error, Result};
-use petgraph::algo::dijkstra;
+use petgraph::algo::{dijkstra, tarjan_scc};
 use petgraph::graph::{Graph, NodeIndex};
+use petgraph::Direction;
 use rayon::prelude::*;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::convert::TryFrom;
@@ -23,26 +27,114 @@ use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use sv_parser::Error as SvParserError;
 use sv_parser::{
-    parse_sv_pp, preprocess, unwrap_node, Define, DefineText, Defines, Locate, RefNode, SyntaxTree,
+    parse_sv_pp, preprocess, preprocess_str, unwrap_node, Define, DefineText, Defines, Locate,
+    NodeEvent, PreprocessedText, RefNode, SyntaxTree,
 };
 use time::OffsetDateTime;
 
 pub mod doc;
 mod printer;
 
+/// Options controlling how the sources are pickled.
+#[derive(Default)]
+pub struct PickleOptions<'a> {
+    /// Optional name prefix.
+    pub prefix: Option<&'a String>,
+    /// Optional name suffix.
+    pub suffix: Option<&'a String>,
+    /// Declarations which are excluded from re-naming.
+    pub exclude_rename: Vec<NamePattern>,
+    /// Declarations which are excluded from the pickled sources.
+    pub exclude: Vec<NamePattern>,
+    /// Top modules, all declarations they do not need are stripped.
+    pub top_modules: Vec<&'a String>,
+    /// Keep `` `define `` statements.
+    pub keep_defines: bool,
+    /// Whether defines were propagated between files.
+    pub propagate_defines: bool,
+    /// How to handle time units.
+    pub timeunits: TimeunitMode,
+    /// Emit the files in compile order.
+    pub sort_files: bool,
+    /// How to handle multiple declarations with the same name.
+    pub duplicate_policy: DuplicatePolicy,
+    /// Renaming settings for each bundle, overriding the global ones.
+    pub bundles: Vec<BundleRenaming>,
+    /// Rename every bundle into its own namespace.
+    pub bundle_namespaces: bool,
+    /// Explicit new names for declarations, overriding prefix and suffix.
+    pub rename_map: HashMap<String, String>,
+    /// Rename the kept macros with the prefix and suffix.
+    pub rename_defines: bool,
+    /// Macros provided by the environment, which are not renamed.
+    pub external_defines: Vec<NamePattern>,
+    /// Move package imports from the compilation-unit scope into the declarations.
+    pub localize_imports: bool,
+    /// Restore the default directives at the end of each file.
+    pub isolate_directives: bool,
+    /// Strip the comments kept during preprocessing.
+    pub strip_comments: bool,
+    /// Strip translate_off regions.
+    pub strip_translate_off: bool,
+    /// Strip concurrent assertions and coverage constructs.
+    pub strip_assertions: bool,
+    /// Strip immediate assertions as well.
+    pub strip_immediate_assertions: bool,
+    /// Keep `` `include `` directives and write the included files to this directory.
+    pub include_dir: Option<&'a Path>,
+    /// Emit `` `line `` directives.
+    pub line_directives: bool,
+    /// Map the lines of the pickle to the original files.
+    pub source_map: bool,
+    /// Write the pickle to multiple files in a directory instead.
+    pub split: Option<(&'a Path, SplitMode)>,
+    /// Modules whose bodies are replaced by black boxes.
+    pub blackbox: Vec<NamePattern>,
+    /// Append a wrapper under the original name of each renamed top module.
+    pub top_wrapper: bool,
+    /// New default values for parameters of modules.
+    pub param_overrides: Vec<ParamOverride>,
+    /// Modules whose declarations are replaced by the ones in other files.
+    pub substitutions: Vec<Substitution>,
+}
+
 pub fn do_pickle<'a>(
-    prefix: Option<&'a String>,
-    suffix: Option<&'a String>,
-    exclude_rename: HashSet<&'a String>,
-    exclude: HashSet<&'a String>,
     library_bundle: LibraryBundle,
     mut syntax_trees: Vec<ParsedFile>,
-    mut out: Box<dyn Write>,
-    top_module: Option<&'a String>,
-    keep_defines: bool,
-    propagate_defines: bool,
-    remove_timeunits: bool,
+    out: Box<dyn Write>,
+    options: PickleOptions<'a>,
 ) -> Result<Pickle<'a>> {
+    let PickleOptions {
+        prefix,
+        suffix,
+        exclude_rename,
+        exclude,
+        top_modules,
+        keep_defines,
+        propagate_defines,
+        timeunits,
+        sort_files,
+        duplicate_policy,
+        bundles,
+        bundle_namespaces,
+        rename_map,
+        rename_defines,
+        external_defines,
+        localize_imports,
+        isolate_directives,
+        strip_comments,
+        strip_translate_off,
+        strip_assertions,
+        strip_immediate_assertions,
+        include_dir,
+        line_directives,
+        source_map,
+        split,
+        blackbox,
+        top_wrapper,
+        param_overrides,
+        substitutions,
+    } = options;
     let mut pickle = Pickle::new(
         // Collect renaming options.
         prefix,
@@ -51,35 +143,48 @@ pub fn do_pickle<'a>(
         exclude,
         library_bundle,
     );
+    pickle.bundles = bundles;
+    pickle.bundle_namespaces = bundle_namespaces;
+    pickle.rename_map = rename_map;
+    pickle.blackbox = blackbox;
+    pickle.param_overrides = param_overrides;
+    let mut out = LineCounter::new(out);
+    let mut mapper = (line_directives || source_map).then(|| SourceMapper::new(line_directives));
+
+    // Substitutes replace the original declarations and are emitted after their files.
+    for substitution in &substitutions {
+        let substitute = pickle.libs.load_substitute(substitution)?;
+        let index =
+            pickle.register_substitution(&syntax_trees, &substitute, &substitution.module)?;
+        syntax_trees.insert(
+            index + 1,
+            ParsedFile {
+                bundle: syntax_trees[index].bundle,
+                ..substitute
+            },
+        );
+    }
 
     // Gather information for pickling.
     for pf in &syntax_trees {
-        // println!("{}", pf.ast);
         for node in &pf.ast {
             trace!("{:#?}", node);
-            match node {
-                // Module declarations.
-                RefNode::ModuleDeclarationAnsi(x) => {
-                    // unwrap_node! gets the nearest ModuleIdentifier from x
-                    let id = unwrap_node!(x, ModuleIdentifier).unwrap();
-                    pickle.register_declaration(&pf.ast, id, pf.path.clone());
-                }
-                RefNode::ModuleDeclarationNonansi(x) => {
-                    let id = unwrap_node!(x, ModuleIdentifier).unwrap();
-                    pickle.register_declaration(&pf.ast, id, pf.path.clone());
-                }
-                // Interface Declaration.
-                RefNode::InterfaceDeclaration(x) => {
-                    let id = unwrap_node!(x, InterfaceIdentifier).unwrap();
-                    pickle.register_declaration(&pf.ast, id, pf.path.clone());
-                }
-                // Package declarations.
-                RefNode::PackageDeclaration(x) => {
-                    let id = unwrap_node!(x, PackageIdentifier).unwrap();
-                    pickle.register_declaration(&pf.ast, id, pf.path.clone());
-                }
-                _ => (),
+        }
+        for (_, id) in unit_declarations(&pf.ast) {
+            // Substituted declarations are not registered.
+            if pickle.is_dropped(&pf.path, &get_identifier(&pf.ast, id.clone()).1) {
+                continue;
             }
+            pickle.register_declaration(&pf.ast, id, pf.path.clone(), pf.bundle);
+        }
+        pickle.register_unit_items(&pf.ast, &pf.path, pf.bundle);
+    }
+
+    pickle.resolve_duplicates(duplicate_policy)?;
+
+    if keep_defines && rename_defines {
+        for pf in &syntax_trees {
+            pickle.register_macro_definitions(&pf.ast, pf.bundle, &external_defines);
         }
     }
 
@@ -113,95 +218,96 @@ pub fn do_pickle<'a>(
             })
             .collect::<Vec<_>>();
 
-        for node in &pf.ast {
-            match node {
-                // Module declarations.
-                RefNode::ModuleDeclarationAnsi(x) => {
-                    // unwrap_node! gets the nearest ModuleIdentifier from x
-                    let parent_id = unwrap_node!(x, ModuleIdentifier).unwrap();
-                    let (parent_name, _) = get_identifier(&pf.ast, parent_id);
-
-                    let my_ref_node: RefNode = x.into();
-                    pickle.find_and_register_instantiations(
-                        &pf.ast,
-                        my_ref_node,
-                        &parent_name,
-                        &mut library_files,
-                    );
-                    for package in global_packages {
-                        pickle.add_dependency_relation(package, &parent_name);
-                    }
-                }
-                RefNode::ModuleDeclarationNonansi(x) => {
-                    let parent_id = unwrap_node!(x, ModuleIdentifier).unwrap();
-                    let (parent_name, _) = get_identifier(&pf.ast, parent_id);
+        for (decl, parent_id) in unit_declarations(&pf.ast) {
+            let (parent_name, parent_loc) = get_identifier(&pf.ast, parent_id);
+            if pickle.is_dropped(&pf.path, &parent_loc) {
+                continue;
+            }
+            let blackbox = pickle.is_blackbox(&parent_name);
+            let parent_name = pickle.resolve(pf.bundle, &parent_name);
+            // Declarations excluded from their bundle are not registered.
+            if !pickle.module_file_map.contains_key(&parent_name) {
+                continue;
+            }
 
-                    let my_ref_node: RefNode = x.into();
-                    pickle.find_and_register_instantiations(
-                        &pf.ast,
-                        my_ref_node,
-                        &parent_name,
-                        &mut library_files,
-                    );
-                    for package in global_packages {
-                        pickle.add_dependency_relation(package, &parent_name);
-                    }
-                }
-                // Interface Declaration.
-                RefNode::InterfaceDeclaration(x) => {
-                    let parent_id = unwrap_node!(x, InterfaceIdentifier).unwrap();
-                    let (parent_name, _) = get_identifier(&pf.ast, parent_id);
-
-                    let my_ref_node: RefNode = x.into();
-                    pickle.find_and_register_instantiations(
-                        &pf.ast,
-                        my_ref_node,
-                        &parent_name,
-                        &mut library_files,
-                    );
-                    for package in global_packages {
-                        pickle.add_dependency_relation(package, &parent_name);
-                    }
-                }
-                // Package declarations.
-                RefNode::PackageDeclaration(x) => {
-                    let parent_id = unwrap_node!(x, PackageIdentifier).unwrap();
-                    let (parent_name, _) = get_identifier(&pf.ast, parent_id);
-
-                    let my_ref_node: RefNode = x.into();
-                    pickle.find_and_register_instantiations(
-                        &pf.ast,
-                        my_ref_node,
-                        &parent_name,
-                        &mut library_files,
-                    );
-                    for package in global_packages {
-                        pickle.add_dependency_relation(package, &parent_name);
+            // Black boxes only depend on what their parameters and ports refer to.
+            match blackbox_items(decl.clone()).filter(|_| blackbox) {
+                Some(items) => {
+                    for (item, _) in items.into_iter().filter(|(_, keep)| *keep) {
+                        pickle.find_and_register_instantiations(
+                            &pf.ast,
+                            item,
+                            &parent_name,
+                            &mut library_files,
+                            pf.bundle,
+                        );
                     }
                 }
-                _ => (),
+                None => pickle.find_and_register_instantiations(
+                    &pf.ast,
+                    decl,
+                    &parent_name,
+                    &mut library_files,
+                    pf.bundle,
+                ),
+            }
+            for package in global_packages {
+                let package = pickle.resolve(pf.bundle, package);
+                pickle.add_dependency_relation(&package, &parent_name);
             }
         }
+        pickle.register_binds(&pf.ast, pf.bundle);
     }
 
     syntax_trees.extend(library_files);
-    write!(
-        out,
+
+    let unmatched = pickle.unmatched_patterns();
+    if !unmatched.is_empty() {
+        let unmatched: Vec<_> = unmatched
+            .iter()
+            .map(|(option, pattern)| format!("{} `{}`", option, pattern))
+            .collect();
+        warn!(
+            "Patterns did not match any declaration:\n\t{}",
+            unmatched.join("\n\t")
+        );
+    }
+
+    pickle.check_param_overrides(&syntax_trees)?;
+
+    // Split files start with the header themselves.
+    let mut preamble = format!(
         "// Compiled by morty-{} / {}\n\n",
         env!("CARGO_PKG_VERSION"),
         OffsetDateTime::now_local().unwrap_or(OffsetDateTime::now_utc())
-    )
-    .unwrap();
+    );
 
-    if let Some(top) = top_module {
+    if !top_modules.is_empty() {
         if propagate_defines {
             warn!(
-                "Pickle might be non-functional as some files can be excluded due to use of --top={}.\
+                "Pickle might be non-functional as some files can be excluded due to use of --top={:?}.\
                 \n\tThis might lead to required components being excluded. Use at your own risk!!!",
-                top
+                top_modules
             );
         }
-        pickle.prune_graph(top)?;
+        pickle.prune_graph(&top_modules)?;
+    }
+
+    // Files split by file are always listed in compile order, units are ordered when splitting.
+    if sort_files || matches!(split, Some((_, SplitMode::Files))) {
+        let paths = syntax_trees
+            .iter()
+            .map(|pf| pf.path.clone())
+            .collect::<Vec<_>>();
+        let order = pickle.compile_order(&paths)?;
+        let position: HashMap<_, _> = order
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, path)| (path.clone(), i))
+            .collect();
+        syntax_trees.sort_by_key(|pf| position[&pf.path]);
+        pickle.file_order = Some(order);
     }
 
     let needed_files = pickle
@@ -210,56 +316,94 @@ pub fn do_pickle<'a>(
         .into_values()
         .collect::<Vec<_>>();
 
+    if let TimeunitMode::Normalize(timescale) = &timeunits {
+        let files = syntax_trees
+            .iter()
+            .filter(|pf| top_modules.is_empty() || needed_files.contains(&pf.path))
+            .collect::<Vec<_>>();
+        if let Some(timescale) = normalize_timescales(&files, timescale.clone()) {
+            preamble.push_str(&format!("`timescale {}\n\n", timescale));
+        }
+    }
+    if split.is_none() {
+        write!(out, "{}", preamble).unwrap();
+    }
+    // The split files, as `(key, file name, contents)`, and the keys of the units within.
+    let mut split_files: Vec<(String, String, String)> = vec![];
+    let mut split_units = HashMap::new();
+    // The wrappers around the top modules, as `(name, contents)`.
+    let mut wrappers = vec![];
+
     // Emit the pickled source files.
+    let mut emitted = HashSet::new();
     for pf in &syntax_trees {
-        if top_module.is_some() && !needed_files.contains(&pf.path) {
+        if !emitted.insert(&pf.path) {
+            warn!("{} is listed multiple times, emitting it once", pf.path);
             continue;
         }
         // For each file, start with a clean replacement table.
         pickle.replace_table.clear();
+        // Files binding into needed units are needed as well.
+        let binds_kept = pickle.register_bind_exclude(&pf.ast, pf.bundle);
+        if !top_modules.is_empty() && !needed_files.contains(&pf.path) && !binds_kept {
+            continue;
+        }
+        // New parameter defaults go first, such that they replace the renamings within.
+        pickle.register_param_overrides(&pf.ast);
+        let local_names = local_names(&pf.ast);
         // Iterate again and check for usage
         for node in &pf.ast {
             match node {
                 // Instantiations, end-labels.
                 RefNode::ModuleIdentifier(x) => {
                     let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_usage(&pf.ast, id);
+                    pickle.register_usage(&pf.ast, id, pf.bundle);
                 }
                 // Interface identifier.
                 RefNode::InterfaceIdentifier(x) => {
                     let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_usage(&pf.ast, id);
+                    pickle.register_usage(&pf.ast, id, pf.bundle);
                 }
                 // Package Qualifier (i.e., explicit package constants).
                 RefNode::ClassScope(x) => {
                     let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_usage(&pf.ast, id);
+                    pickle.register_usage(&pf.ast, id, pf.bundle);
                 }
                 // Package Import.
                 RefNode::PackageIdentifier(x) => {
                     let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_usage(&pf.ast, id);
-                }
-                // Check whether we want to exclude the given module from the file sources.
-                RefNode::ModuleDeclarationAnsi(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_exclude(&pf.ast, id, Locate::try_from(x).unwrap())
+                    pickle.register_usage(&pf.ast, id, pf.bundle);
                 }
-                RefNode::ModuleDeclarationNonansi(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_exclude(&pf.ast, id, Locate::try_from(x).unwrap())
+                // Programs, checkers, UDPs and configs (end-labels, configured cells).
+                RefNode::ProgramIdentifier(_)
+                | RefNode::CheckerIdentifier(_)
+                | RefNode::UdpIdentifier(_)
+                | RefNode::ConfigIdentifier(_)
+                | RefNode::CellIdentifier(_) => {
+                    pickle.register_usage(&pf.ast, node, pf.bundle);
                 }
-                RefNode::InterfaceDeclaration(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_exclude(&pf.ast, id, Locate::try_from(x).unwrap())
+                // Classes, including class types which cannot be told apart from other types.
+                RefNode::ClassIdentifier(_)
+                | RefNode::NetTypeIdentifier(_)
+                | RefNode::TypeIdentifier(_) => {
+                    pickle.register_usage(&pf.ast, node, pf.bundle);
                 }
-                RefNode::PackageDeclaration(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_exclude(&pf.ast, id, Locate::try_from(x).unwrap())
+                // Hierarchical references rooted at a unit, including `defparam` and bind targets.
+                RefNode::HierarchicalIdentifier(x) => {
+                    pickle.register_hierarchical_usage(&pf.ast, x, pf.bundle, &local_names);
                 }
                 RefNode::TimeunitsDeclaration(x) => {
                     let loc = Locate::try_from(x).unwrap();
-                    if remove_timeunits {
+                    if timeunits != TimeunitMode::Keep {
+                        pickle
+                            .replace_table
+                            .push((loc.offset, loc.len, "".to_string()));
+                    }
+                }
+                // The normalized `timescale replaces all others.
+                RefNode::TimescaleCompilerDirective(x) => {
+                    if let TimeunitMode::Normalize(_) = timeunits {
+                        let loc = node_locate(RefNode::TimescaleCompilerDirective(x)).unwrap();
                         pickle
                             .replace_table
                             .push((loc.offset, loc.len, "".to_string()));
@@ -269,10 +413,50 @@ pub fn do_pickle<'a>(
             }
         }
 
+        pickle.register_unit_item_usages(&pf.ast, &pf.path);
+
+        if localize_imports {
+            pickle.register_global_imports(&pf.ast, &pf.path, pf.bundle);
+        }
+
+        // Check whether we want to exclude the given declarations from the file sources.
+        for (decl, id) in unit_declarations(&pf.ast) {
+            pickle.register_exclude(
+                &pf.ast,
+                id.clone(),
+                node_locate(decl.clone()).unwrap(),
+                &pf.path,
+                pf.bundle,
+            );
+            pickle.register_blackbox(&pf.ast, decl, id, &pf.path, pf.bundle);
+        }
+
+        if strip_translate_off {
+            pickle.register_translate_off(pf);
+        }
+
+        if strip_assertions {
+            pickle.register_assertions(&pf.ast, &pf.path, strip_immediate_assertions);
+        }
+
+        // Comments which were kept during preprocessing.
+        if strip_comments {
+            for node in &pf.ast {
+                if let RefNode::Comment(x) = node {
+                    let loc = x.nodes.0;
+                    pickle
+                        .replace_table
+                        .push((loc.offset, loc.len, "".to_string()));
+                }
+            }
+        }
+
         // Find macros to be removed
         let mut new_replace_table = Vec::new();
 
-        if !keep_defines {
+        if keep_defines {
+            pickle.register_macro_usages(&pf.ast, pf.bundle);
+        } else {
             for node in &pf.ast {
                 if let RefNode::TextMacroDefinition(x) = node {
                     let loc = Locate::try_from(x).unwrap();
@@ -283,19 +467,49 @@ pub fn do_pickle<'a>(
 
         new_replace_table.append(&mut pickle.replace_table);
 
+        // Included text goes first, such that it replaces the replacements within.
+        if include_dir.is_some() {
+            let includes = pickle.register_includes(&pf.includes, &pf.path, pf.bundle);
+            new_replace_table.splice(0..0, includes);
+        }
+
+        // Emit the partially preprocessed text instead, if there is one.
+        let source = match &pf.partial {
+            Some(partial) => {
+                new_replace_table = partial.map(&pf.path, new_replace_table);
+                new_replace_table.extend(pickle.register_unparsed_usages(
+                    &partial.text,
+                    &partial.unparsed(),
+                    pf.bundle,
+                ));
+                new_replace_table
+                    .extend(pickle.register_partial_macro_usages(&partial.text, pf.bundle));
+                &partial.text
+            }
+            None => &pf.source,
+        };
+
         // sort replace table
-        new_replace_table.sort_by(|a, b| a.0.cmp(&b.0));
+        new_replace_table.sort_by_key(|a| a.0);
+        new_replace_table.dedup();
 
         // Error on overlapping -> correct overlapping!
-        for window in new_replace_table.windows(2) {
-            if window[0].0 + window[0].1 > window[1].0 {
-                eprintln!("Offset error, please contact Michael\n{:?}", window[0]);
+        // Replacements fully contained in a previous one (e.g., a renamed instantiation inside a
+        // stripped module) are fine, as they are skipped below.
+        let mut end = 0;
+        for (offset, len, _) in new_replace_table.iter() {
+            if *offset < end && offset + len > end {
+                eprintln!("Offset error, please contact Michael\n{:?}", (offset, len));
             }
+            end = end.max(offset + len);
         }
 
         // Replace according to `replace_table`.
         // Apply the replacements.
         debug!("Replace Table: {:?}", new_replace_table);
+        let mut text = String::new();
+        // Runs of the text taken from the source, as `(start, end, offset in the source)`.
+        let mut runs = vec![];
         let mut pos = 0;
         for (offset, len, repl) in new_replace_table.iter() {
             // Because we are partially stripping modules it can be the case that we don't need to apply some of the upcoming replacements.
@@ -303,33 +517,288 @@ pub fn do_pickle<'a>(
                 continue;
             }
             trace!("Replacing: {},{}, {}", offset, len, repl);
-            write!(out, "{}", &pf.source[pos..*offset]).unwrap();
-            write!(out, "{}", repl).unwrap();
+            runs.push((text.len(), text.len() + offset - pos, pos));
+            text.push_str(&source[pos..*offset]);
+            text.push_str(repl);
             pos = offset + len;
         }
-        write!(out, "{}", &pf.source[pos..]).unwrap();
+        runs.push((text.len(), text.len() + source.len() - pos, pos));
+        text.push_str(&source[pos..]);
         // Make sure that each file ends with a newline.
-        if !pf.source.ends_with('\n') {
-            writeln!(out).unwrap();
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        // Restore the default directives for the following files.
+        if isolate_directives {
+            let mut state = DirectiveState::new(&pf.ast);
+            // The normalized `timescale applies to all files.
+            if let TimeunitMode::Normalize(_) = timeunits {
+                state.timescale = false;
+            }
+            for reset in state.resets() {
+                text.push_str(&format!("{}\n", reset));
+            }
+        }
+        if top_wrapper {
+            for (decl, id) in unit_declarations(&pf.ast) {
+                let (name, loc) = get_identifier(&pf.ast, id.clone());
+                if top_modules.iter().any(|top| **top == name) && !pickle.is_dropped(&pf.path, &loc)
+                {
+                    if let Some(wrapper) = pickle.top_wrapper(pf, &text, &runs, decl, id) {
+                        wrappers.push((name, wrapper));
+                    }
+                }
+            }
+        }
+        let pieces = match split {
+            None => {
+                match &mut mapper {
+                    Some(mapper) => mapper.write(&mut out, pf, &text, &runs)?,
+                    None => write!(out, "{}", text).unwrap(),
+                }
+                continue;
+            }
+            Some((_, SplitMode::Files)) => vec![(pf.path.clone(), 0, text.len())],
+            Some((_, SplitMode::Units)) => pickle.split_units(pf, &text, &runs),
+        };
+        for (key, start, end) in pieces {
+            let name = match split {
+                Some((_, SplitMode::Units)) if key != pf.path => {
+                    split_units.insert(key.clone(), key.clone());
+                    let name = pickle.rename_table.get(&key).unwrap_or(&key);
+                    format!("{}.sv", name)
+                }
+                _ => Path::new(&pf.path)
+                    .file_name()
+                    .map_or(key.clone(), |n| n.to_string_lossy().to_string()),
+            };
+            let name = unique_file_name(&name, |n| split_files.iter().any(|(_, f, _)| f == n));
+            // Only keep the runs within the piece.
+            let runs: Vec<_> = runs
+                .iter()
+                .filter_map(|&(s, e, offset)| {
+                    let (s_clip, e_clip) = (s.max(start), e.min(end));
+                    (s_clip < e_clip).then(|| (s_clip - start, e_clip - start, offset + s_clip - s))
+                })
+                .collect();
+            let mut buf = LineCounter::new(preamble.clone().into_bytes());
+            match &mut mapper {
+                Some(mapper) => mapper.write(&mut buf, pf, &text[start..end], &runs)?,
+                None => write!(buf, "{}", &text[start..end]).unwrap(),
+            }
+            let contents = String::from_utf8(buf.inner).unwrap();
+            split_files.push((key, name, contents));
+        }
+    }
+
+    if let Some((dir, mode)) = split {
+        let keys: Vec<_> = split_files.iter().map(|(key, _, _)| key.clone()).collect();
+        // Files are already sorted in compile order, units are sorted now.
+        let order = match mode {
+            SplitMode::Files => keys,
+            SplitMode::Units => pickle.order_by(&keys, &split_units)?,
+        };
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create `{}`", dir.display()))?;
+        for key in &order {
+            let (_, name, contents) = split_files.iter().find(|(k, _, _)| k == key).unwrap();
+            let path = dir.join(name);
+            std::fs::write(&path, contents)
+                .with_context(|| format!("Failed to write `{}`", path.display()))?;
+            writeln!(out, "{}", path.display()).unwrap();
+        }
+        for (name, wrapper) in &wrappers {
+            let name = unique_file_name(&format!("{}.sv", name), |n| {
+                split_files.iter().any(|(_, f, _)| f == n)
+            });
+            let path = dir.join(name);
+            std::fs::write(&path, format!("{}{}", preamble, wrapper))
+                .with_context(|| format!("Failed to write `{}`", path.display()))?;
+            writeln!(out, "{}", path.display()).unwrap();
+        }
+    } else {
+        // The wrappers follow all files, as they instantiate the renamed top modules.
+        for (_, wrapper) in &wrappers {
+            write!(out, "\n{}", wrapper).unwrap();
         }
     }
 
+    if let Some(dir) = include_dir {
+        pickle.write_headers(dir)?;
+    }
+
+    if let Some(mapper) = mapper {
+        pickle.source_map = mapper.entries;
+    }
+
     Ok(pickle)
 }
 
+/// Map a range of the preprocessed source of a file to the range of the text emitted for it, as
+/// `(start, end)`, or `None` if the range was stripped.
+fn text_range(
+    pf: &ParsedFile,
+    runs: &[(usize, usize, usize)],
+    loc: Locate,
+) -> Option<(usize, usize)> {
+    // Map a position in the emitted source to the text.
+    let position = |pos: usize| {
+        let pos = match &pf.partial {
+            Some(partial) => partial.origin(pos.checked_sub(1)?)? + 1,
+            None => pos,
+        };
+        runs.iter()
+            .find(|&&(s, e, offset)| offset <= pos && pos <= offset + e - s)
+            .or_else(|| runs.iter().find(|&&(_, _, offset)| offset > pos))
+            .map(|&(s, _, offset)| s + pos.saturating_sub(offset))
+    };
+    match (position(loc.offset + 1), position(loc.offset + loc.len)) {
+        (Some(start), Some(end)) if start < end => Some((start - 1, end)),
+        _ => None,
+    }
+}
+
+/// How to split the pickle into multiple files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitMode {
+    /// One file per input file.
+    Files,
+    /// One file per module, interface, package, etc.
+    Units,
+}
+
+/// Make a file name unique by appending a number to its stem.
+fn unique_file_name(name: &str, taken: impl Fn(&str) -> bool) -> String {
+    let mut unique = name.to_string();
+    let mut i = 1;
+    while taken(&unique) {
+        unique = match name.rsplit_once('.') {
+            Some((stem, ext)) => format!("{}_{}.{}", stem, i, ext),
+            None => format!("{}_{}", name, i),
+        };
+        i += 1;
+    }
+    unique
+}
+
+/// A writer which counts the lines written.
+struct LineCounter<W> {
+    inner: W,
+    lines: usize,
+}
+
+impl<W> LineCounter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, lines: 0 }
+    }
+}
+
+impl<W: Write> Write for LineCounter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.lines += buf[..n].iter().filter(|&&c| c == b'\n').count();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Tracks where the lines of the pickle stem from, emitting `` `line `` directives if requested.
+struct SourceMapper {
+    line_directives: bool,
+    /// Offsets of the lines of the original files.
+    line_starts: HashMap<PathBuf, Vec<usize>>,
+    entries: Vec<SourceMapEntry>,
+}
+
+impl SourceMapper {
+    fn new(line_directives: bool) -> Self {
+        Self {
+            line_directives,
+            line_starts: HashMap::new(),
+            entries: vec![],
+        }
+    }
+
+    // The line number of an offset in an original file.
+    fn line(&mut self, path: &Path, offset: usize) -> usize {
+        let starts = self
+            .line_starts
+            .entry(path.to_path_buf())
+            .or_insert_with(|| {
+                let text = std::fs::read_to_string(path).unwrap_or_default();
+                let newlines = text.match_indices('\n').map(|(i, _)| i + 1);
+                std::iter::once(0).chain(newlines).collect()
+            });
+        starts.partition_point(|&start| start <= offset)
+    }
+
+    /// Write the pickled text of a file, given the runs `(start, end, offset)` of the text which
+    /// were taken from the emitted source of the file.
+    fn write<W: Write>(
+        &mut self,
+        out: &mut LineCounter<W>,
+        pf: &ParsedFile,
+        text: &str,
+        runs: &[(usize, usize, usize)],
+    ) -> Result<()> {
+        // The origin of the next line if it continues the current mapping.
+        let mut expected: Option<(PathBuf, usize)> = None;
+        let mut start = 0;
+        for content in text.split_inclusive('\n') {
+            let end = start + content.len();
+            // The first character of the line which stems from the source, ignoring whitespace
+            // whose origin is imprecise.
+            let origin = runs
+                .iter()
+                .filter(|&&(s, e, _)| e > start.max(s) && s < end)
+                .find_map(|&(s, e, offset)| {
+                    let from = start.max(s);
+                    let skip = text[from..e.min(end)].find(|c: char| !c.is_whitespace())?;
+                    pf.origin(offset + from - s + skip)
+                })
+                .map(|(path, offset)| (path.to_path_buf(), self.line(path, offset)));
+            if origin.is_some() && origin != expected {
+                let (path, line) = origin.clone().unwrap();
+                if self.line_directives {
+                    writeln!(out, "`line {} \"{}\" 0", line, path.display())?;
+                }
+                self.entries.push(SourceMapEntry {
+                    pickle_line: out.lines + 1,
+                    lines: 0,
+                    file: path.to_string_lossy().to_string(),
+                    line,
+                });
+                expected = origin;
+            }
+            if let Some((_, line)) = &mut expected {
+                self.entries.last_mut().unwrap().lines += 1;
+                *line += 1;
+            }
+            write!(out, "{}", content)?;
+            start = end;
+        }
+        Ok(())
+    }
+}
+
 pub fn build_syntax_tree(
-    file_list: &Vec<FileBundle>,
+    file_list: &[FileBundle],
     strip_comments: bool,
     ignore_unparseable: bool,
     propagate_defines: bool,
     force_sequential: bool,
+    undefines: Option<&[String]>,
+    keep_includes: bool,
 ) -> Result<Vec<ParsedFile>> {
     // Parse the input files.
     let mut syntax_trees = vec![];
 
     let mut internal_defines: Defines = HashMap::new();
 
-    for bundle in file_list {
+    for (i, bundle) in file_list.iter().enumerate() {
         let bundle_include_dirs: Vec<_> = bundle.include_dirs.iter().map(Path::new).collect();
 
         if propagate_defines {
@@ -338,6 +807,13 @@ pub fn build_syntax_tree(
             internal_defines = defines_to_sv_parser(&bundle.defines);
         }
 
+        // Only resolve the conditionals on the bundle's defines.
+        let unifdef = undefines.map(|undefines| Unifdef {
+            defines: &bundle.defines,
+            undefines,
+            include_dirs: &bundle_include_dirs,
+        });
+
         // For each file in the file bundle preprocess and parse it.
         // Use a neat trick of `collect` here, which allows you to collect a
         // `Result<T>` iterator into a `Result<Vec<T>>`, i.e. bubbling up the
@@ -349,6 +825,8 @@ pub fn build_syntax_tree(
                     &bundle_include_dirs,
                     &internal_defines,
                     strip_comments,
+                    unifdef.as_ref(),
+                    keep_includes,
                 )?;
                 if propagate_defines {
                     internal_defines.extend(pf.defines.clone());
@@ -368,6 +846,8 @@ pub fn build_syntax_tree(
                     &bundle_include_dirs,
                     &internal_defines,
                     strip_comments,
+                    unifdef.as_ref(),
+                    keep_includes,
                 )
             });
             if ignore_unparseable {
@@ -377,7 +857,10 @@ pub fn build_syntax_tree(
                 tmp.collect::<Result<Vec<ParsedFile>>>()?
             }
         };
-        syntax_trees.extend(v);
+        syntax_trees.extend(v.into_iter().map(|pf| ParsedFile {
+            bundle: Some(i),
+            ..pf
+        }));
     }
 
     Ok(syntax_trees)
@@ -393,7 +876,10 @@ pub fn just_preprocess(syntax_trees: Vec<ParsedFile>, mut out: Box<dyn Write>) -
     .unwrap();
     for pf in syntax_trees {
         eprintln!("{}:", pf.path);
-        writeln!(out, "{:}", pf.source).unwrap();
+        match &pf.partial {
+            Some(partial) => writeln!(out, "{:}", partial.text).unwrap(),
+            None => writeln!(out, "{:}", pf.source).unwrap(),
+        }
     }
     Ok(())
 }
@@ -411,7 +897,7 @@ pub fn write_manifest(
     file_list: Vec<FileBundle>,
     include_dirs: Vec<String>,
     defines: HashMap<String, Option<String>>,
-    top_module: Option<&String>,
+    top_modules: Vec<&String>,
 ) -> Result<()> {
     let mut undef_modules = Vec::new();
 
@@ -422,20 +908,20 @@ pub fn write_manifest(
         }
     }
 
-    let mut top_modules = Vec::new();
+    let mut tops = Vec::new();
 
     // find top modules
-    match top_module {
-        Some(x) => {
-            top_modules.push(pickle.rename_table[x].to_string());
-        }
-        None => {
-            for new_name in pickle.rename_table.values() {
-                if !pickle.inst_table.contains(new_name) {
-                    top_modules.push(new_name.to_string());
-                }
+    if top_modules.is_empty() {
+        for new_name in pickle.rename_table.values() {
+            if !pickle.inst_table.contains(new_name) {
+                tops.push(new_name.to_string());
             }
         }
+    } else {
+        for x in &top_modules {
+            let unit = pickle.resolve_top(x)?;
+            tops.push(pickle.rename_table.get(&unit).unwrap_or(&unit).to_string());
+        }
     }
 
     let mut base_files = Vec::new();
@@ -448,14 +934,19 @@ pub fn write_manifest(
     for mut bundle in file_list {
         if bundle.include_dirs == include_dirs && bundle.defines == defines {
             base_files.extend(bundle.files.clone());
-            if top_module.is_some() {
+            if !top_modules.is_empty() {
                 base_files.retain(|v| pickled_files.clone().contains(v));
             }
         } else {
-            if top_module.is_some() {
+            if !top_modules.is_empty() {
                 bundle.files.retain(|v| pickled_files.contains(v));
             }
             if !bundle.files.is_empty() {
+                // the pickled sources are already renamed
+                bundle.prefix = None;
+                bundle.suffix = None;
+                bundle.exclude_rename.clear();
+                bundle.exclude.clear();
                 bundles.push(bundle);
             }
         }
@@ -463,16 +954,36 @@ pub fn write_manifest(
     base_files.extend(pickle.used_libs);
     if !base_files.is_empty() {
         bundles.push(FileBundle {
+            name: None,
             include_dirs,
             export_incdirs: HashMap::new(),
             defines,
             files: base_files,
+            prefix: None,
+            suffix: None,
+            exclude_rename: vec![],
+            exclude: vec![],
         });
     }
 
+    // sort the sources in compile order if requested
+    if let Some(order) = &pickle.file_order {
+        let position: HashMap<_, _> = order
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, path)| (path, i))
+            .collect();
+        let get_position = |file: &String| position.get(file).copied().unwrap_or(usize::MAX);
+        for bundle in &mut bundles {
+            bundle.files.sort_by_key(get_position);
+        }
+        bundles.sort_by_key(|bundle| bundle.files.iter().map(get_position).min());
+    }
+
     let json = serde_json::to_string_pretty(&Manifest {
         sources: bundles,
-        tops: top_modules,
+        tops,
         undefined: undef_modules,
     })
     .unwrap();
@@ -484,6 +995,113 @@ pub fn write_manifest(
     Ok(())
 }
 
+/// Collect the renaming settings of each file bundle.
+///
+/// With `namespaces`, bundles without an explicit prefix are prefixed with `<prefix><name>_` to
+/// rename each bundle into its own namespace. Bundles without a name are numbered.
+pub fn bundle_renaming(
+    file_list: &[FileBundle],
+    prefix: Option<&String>,
+    namespaces: bool,
+) -> Result<Vec<BundleRenaming>> {
+    file_list
+        .iter()
+        .enumerate()
+        .map(|(i, bundle)| {
+            let namespace_prefix = match &bundle.name {
+                Some(name) => format!("{}{}_", prefix.map_or("", |p| p.as_str()), name),
+                None => format!("{}bundle{}_", prefix.map_or("", |p| p.as_str()), i),
+            };
+            Ok(BundleRenaming {
+                prefix: bundle
+                    .prefix
+                    .clone()
+                    .or_else(|| namespaces.then_some(namespace_prefix)),
+                suffix: bundle.suffix.clone(),
+                exclude_rename: NamePattern::parse_all(&bundle.exclude_rename)?,
+                exclude: NamePattern::parse_all(&bundle.exclude)?,
+            })
+        })
+        .collect()
+}
+
+/// Read a map of explicit new names for declarations from a JSON or TOML file.
+pub fn read_rename_map(rename_map_file: &str) -> Result<HashMap<String, String>> {
+    let content = std::fs::read_to_string(rename_map_file)
+        .with_context(|| format!("Failed to read rename map `{}`", rename_map_file))?;
+    if Path::new(rename_map_file).extension() == Some(OsStr::new("toml")) {
+        toml::from_str(&content)
+            .with_context(|| format!("Failed to parse rename map `{}`", rename_map_file))
+    } else {
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse rename map `{}`", rename_map_file))
+    }
+}
+
+/// Write the renamed declarations, their kind and source file as JSON to file
+pub fn write_rename_map(pickle: &Pickle, rename_map_file: &str) -> Result<()> {
+    // with per-bundle namespaces the units are tracked under their new name
+    let mut original_names = HashMap::new();
+    for (name, decls) in &pickle.namespaces {
+        for (_, unit) in decls {
+            original_names.insert(unit, name);
+        }
+    }
+
+    let mut entries = pickle
+        .rename_table
+        .iter()
+        .map(|(unit, new_name)| RenameEntry {
+            name: original_names
+                .get(unit)
+                .copied()
+                .unwrap_or(unit)
+                .to_string(),
+            new_name: new_name.to_string(),
+            kind: pickle.unit_kinds[unit],
+            file: pickle.module_file_map[unit].to_string(),
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| (&a.name, &a.new_name).cmp(&(&b.name, &b.new_name)));
+
+    let json = serde_json::to_string_pretty(&entries).unwrap();
+
+    let path = Path::new(rename_map_file);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    writeln!(out, "{}", json).unwrap();
+
+    Ok(())
+}
+
+/// Write the source map of the pickle as JSON to file
+pub fn write_source_map(pickle: &Pickle, source_map_file: &str) -> Result<()> {
+    let json = serde_json::to_string_pretty(&pickle.source_map).unwrap();
+
+    let path = Path::new(source_map_file);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    writeln!(out, "{}", json).unwrap();
+
+    Ok(())
+}
+
+/// Resolve a line of a pickle to the file and line it stems from, using its source map.
+pub fn resolve_source_map(source_map_file: &str, line: usize) -> Result<(String, usize)> {
+    let content = std::fs::read_to_string(source_map_file)
+        .with_context(|| format!("Failed to read source map `{}`", source_map_file))?;
+    let entries: Vec<SourceMapEntry> = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse source map `{}`", source_map_file))?;
+    entries
+        .iter()
+        .find(|e| (e.pickle_line..e.pickle_line + e.lines).contains(&line))
+        .map(|e| (e.file.clone(), e.line + line - e.pickle_line))
+        .ok_or_else(|| {
+            anyhow!(
+                "Line {} of the pickle does not stem from a source file",
+                line
+            )
+        })
+}
+
 /// Write module graph to file
 pub fn write_dot_graph(pickle: &Pickle, graph_file: &str) -> Result<()> {
     let path = Path::new(graph_file);
@@ -509,9 +1127,15 @@ pub struct Pickle<'a> {
     /// Optional name suffix.
     pub suffix: Option<&'a String>,
     /// Declarations which are excluded from re-naming.
-    pub exclude_rename: HashSet<&'a String>,
+    pub exclude_rename: Vec<NamePattern>,
     /// Declarations which are excluded from the pickled sources.
-    pub exclude: HashSet<&'a String>,
+    pub exclude: Vec<NamePattern>,
+    /// Modules whose bodies are replaced by black boxes.
+    pub blackbox: Vec<NamePattern>,
+    /// New default values for parameters of modules.
+    pub param_overrides: Vec<ParamOverride>,
+    /// Patterns which matched at least one declaration, with the option they were given with.
+    pub matched_patterns: HashSet<(&'static str, String)>,
     /// Table containing thing that should be re-named.
     pub rename_table: HashMap<String, String>,
     /// Locations of text which should be replaced.
@@ -528,14 +1152,43 @@ pub struct Pickle<'a> {
     pub module_graph_nodes: HashMap<String, NodeIndex>,
     /// Map module name to declaration file
     pub module_file_map: HashMap<String, String>,
+    /// Declarations which were pruned as they are not needed by the top module.
+    pub pruned: HashSet<String>,
+    /// Order of the pickled files, if they were sorted in compile order.
+    pub file_order: Option<Vec<String>>,
+    /// Map declaration name to the files and offsets it is declared at.
+    pub declaration_sites: HashMap<String, Vec<(String, usize)>>,
+    /// Duplicate declarations (file and offset) which are stripped from the sources.
+    pub dropped_declarations: HashSet<(String, usize)>,
+    /// Renaming settings for each bundle, overriding the global ones.
+    pub bundles: Vec<BundleRenaming>,
+    /// Whether every bundle is renamed into its own namespace.
+    pub bundle_namespaces: bool,
+    /// Explicit new names for declarations, overriding prefix and suffix.
+    pub rename_map: HashMap<String, String>,
+    /// Map declarations to their kind.
+    pub unit_kinds: HashMap<String, UnitKind>,
+    /// Map declaration names to the bundles declaring them and the resulting unit names.
+    pub namespaces: HashMap<String, Vec<(Option<usize>, String)>>,
+    /// Map macros defined in the sources to the bundles defining them and their new names.
+    pub macro_table: HashMap<String, Vec<(Option<usize>, String)>>,
+    /// Map each file to its renamed compilation-unit scope items and their new names.
+    pub unit_items: HashMap<String, HashMap<String, String>>,
+    /// Map included header files to their names in the include directory and their bundle.
+    pub headers: HashMap<PathBuf, (String, Option<usize>)>,
+    /// Map included header files to the renamed compilation-unit scope items of the files
+    /// including them.
+    pub header_unit_items: HashMap<PathBuf, HashMap<String, String>>,
+    /// Map ranges of lines in the pickle to the files they stem from.
+    pub source_map: Vec<SourceMapEntry>,
 }
 
 impl<'a> Pickle<'a> {
     pub fn new(
         prefix: Option<&'a String>,
         suffix: Option<&'a String>,
-        exclude_rename: HashSet<&'a String>,
-        exclude: HashSet<&'a String>,
+        exclude_rename: Vec<NamePattern>,
+        exclude: Vec<NamePattern>,
         libs: LibraryBundle,
     ) -> Self {
         Self {
@@ -543,6 +1196,9 @@ impl<'a> Pickle<'a> {
             suffix,
             exclude_rename,
             exclude,
+            blackbox: vec![],
+            param_overrides: vec![],
+            matched_patterns: HashSet::new(),
             // Create a rename table.
             rename_table: HashMap::new(),
             replace_table: vec![],
@@ -553,37 +1209,163 @@ impl<'a> Pickle<'a> {
             module_graph: Graph::new(),
             module_graph_nodes: HashMap::new(),
             module_file_map: HashMap::new(),
+            pruned: HashSet::new(),
+            file_order: None,
+            declaration_sites: HashMap::new(),
+            dropped_declarations: HashSet::new(),
+            bundles: vec![],
+            bundle_namespaces: false,
+            rename_map: HashMap::new(),
+            unit_kinds: HashMap::new(),
+            namespaces: HashMap::new(),
+            macro_table: HashMap::new(),
+            unit_items: HashMap::new(),
+            header_unit_items: HashMap::new(),
+            headers: HashMap::new(),
+            source_map: vec![],
+        }
+    }
+
+    /// Compute the new name of `name` declared in a file of `bundle`, or `None` if it is excluded
+    /// from renaming.
+    fn new_name(&mut self, bundle: Option<usize>, name: &str) -> Option<String> {
+        // An explicit name overrides the prefix and suffix.
+        if let Some(name) = self.rename_map.get(name) {
+            return Some(name.clone());
         }
+        self.affixed_name(bundle, name)
+    }
+
+    /// Apply the prefix and suffix of `bundle` to `name`, or `None` if it is excluded from
+    /// renaming.
+    fn affixed_name(&mut self, bundle: Option<usize>, name: &str) -> Option<String> {
+        let (bundles, matched) = (&self.bundles, &mut self.matched_patterns);
+        let settings = bundle.and_then(|b| bundles.get(b));
+        let exclude_rename = match_patterns(matched, EXCLUDE_RENAME, &self.exclude_rename, name)
+            | settings.is_some_and(|s| {
+                match_patterns(matched, BUNDLE_EXCLUDE_RENAME, &s.exclude_rename, name)
+            });
+        if exclude_rename {
+            return None;
+        }
+        let mut new_name = name.to_string();
+        if let Some(prefix) = settings.and_then(|s| s.prefix.as_ref()).or(self.prefix) {
+            new_name = format!("{}{}", prefix, new_name);
+        }
+        if let Some(suffix) = settings.and_then(|s| s.suffix.as_ref()).or(self.suffix) {
+            new_name = format!("{}{}", new_name, suffix);
+        }
+        Some(new_name)
     }
 
     /// Register a declaration such as a package or module.
-    pub fn register_declaration(&mut self, syntax_tree: &SyntaxTree, id: RefNode, file: String) {
+    pub fn register_declaration(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        id: RefNode,
+        file: String,
+        bundle: Option<usize>,
+    ) {
+        let kind = UnitKind::from(&id);
         let (module_name, loc) = get_identifier(syntax_tree, id);
         info!("module_name: {:?}", module_name);
-        self.module_graph_nodes.insert(
-            module_name.clone(),
-            self.module_graph.add_node(module_name.clone()),
-        );
-        self.module_file_map.insert(module_name.clone(), file);
-        if self.exclude_rename.contains(&module_name) || self.exclude.contains(&module_name) {
+        let (bundles, matched) = (&self.bundles, &mut self.matched_patterns);
+        // Declarations excluded from their bundle are not considered declared, such that
+        // references resolve to the ones in other bundles.
+        let settings = bundle.and_then(|b| bundles.get(b));
+        if settings
+            .is_some_and(|s| match_patterns(matched, BUNDLE_EXCLUDE, &s.exclude, &module_name))
+        {
             return;
         }
-        let mut new_name = module_name.clone();
-        if let Some(prefix) = self.prefix {
-            new_name = format!("{}{}", prefix, new_name);
+        // Globally excluded declarations are stripped from the sources and not renamed.
+        let new_name = match match_patterns(matched, EXCLUDE, &self.exclude, &module_name) {
+            true => None,
+            false => self.new_name(bundle, &module_name),
+        };
+        let rename = new_name.is_some();
+        let new_name = new_name.unwrap_or_else(|| module_name.clone());
+
+        // With per-bundle namespaces, the same name may be declared once per bundle. Each of
+        // these declarations is tracked under its new name.
+        let unit = if !self.bundle_namespaces {
+            module_name.clone()
+        } else {
+            let unit = if rename {
+                new_name.clone()
+            } else {
+                module_name.clone()
+            };
+            let decls = self.namespaces.entry(module_name.clone()).or_default();
+            if !decls.contains(&(bundle, unit.clone())) {
+                decls.push((bundle, unit.clone()));
+            }
+            unit
+        };
+
+        if !self.module_graph_nodes.contains_key(&unit) {
+            self.module_graph_nodes
+                .insert(unit.clone(), self.module_graph.add_node(unit.clone()));
         }
-        if let Some(suffix) = self.suffix {
-            new_name = format!("{}{}", new_name, suffix);
+        self.declaration_sites
+            .entry(unit.clone())
+            .or_default()
+            .push((file.clone(), loc.offset));
+        self.module_file_map.insert(unit.clone(), file);
+        self.unit_kinds.insert(unit.clone(), kind);
+        if !rename {
+            return;
         }
         debug!("Declaration `{}`: {:?}", module_name, loc);
-        self.rename_table.insert(module_name, new_name);
+        self.rename_table.insert(unit, new_name);
+    }
+
+    /// Resolve a reference to `name` from a file of `bundle` to the unit it refers to.
+    ///
+    /// Without per-bundle namespaces this is the name itself. Otherwise a reference resolves to
+    /// the declaration in the same bundle, then to the one in the closest preceding bundle, and
+    /// finally to the one in the closest following bundle. Library files (`None`) come after all
+    /// bundles.
+    pub fn resolve(&self, bundle: Option<usize>, name: &str) -> String {
+        let decls = match self.namespaces.get(name) {
+            Some(decls) => decls,
+            None => return name.to_string(),
+        };
+        closest_bundle(decls, bundle).unwrap().clone()
+    }
+
+    /// Resolve a top module given by the user to its unit. A name declared in several bundles is
+    /// ambiguous and has to be given as the name of the unit of one of them.
+    pub fn resolve_top(&self, name: &str) -> Result<String> {
+        match self.namespaces.get(name) {
+            Some(decls) if decls.len() > 1 => {
+                let units: Vec<_> = decls
+                    .iter()
+                    .map(|(_, unit)| format!("`{}`", unit))
+                    .collect();
+                Err(anyhow!(
+                    "Top module `{}` is declared in several bundles, use one of {}",
+                    name,
+                    units.join(", ")
+                ))
+            }
+            _ => Ok(self.resolve(None, name)),
+        }
+    }
+
+    /// The new name of the macro `name` used in a file of `bundle`, resolved to the definitions
+    /// of the bundles like references to units.
+    fn macro_name(&self, bundle: Option<usize>, name: &str) -> Option<&String> {
+        closest_bundle(self.macro_table.get(name)?, bundle)
     }
 
     pub fn register_instantiation(&mut self, syntax_tree: &SyntaxTree, id: RefNode) {
         let (inst_name, _) = get_identifier(syntax_tree, id.clone());
+        let inst_name = self.resolve(None, &inst_name);
         self.inst_table.insert(inst_name.clone());
 
         if let Some((parent_name, _)) = get_calling_module(syntax_tree, id) {
+            let parent_name = self.resolve(None, &parent_name);
             self.add_dependency_relation(&inst_name, &parent_name);
         }
     }
@@ -593,11 +1375,21 @@ impl<'a> Pickle<'a> {
         syntax_tree: &SyntaxTree,
         id: RefNode,
         parent_name: &str,
+        bundle: Option<usize>,
     ) {
         let (inst_name, _) = get_identifier(syntax_tree, id.clone());
-        self.inst_table.insert(inst_name.clone());
+        let unit = self.resolve(bundle, &inst_name);
+        if let Some(decls) = self.namespaces.get(&inst_name) {
+            if decls.len() > 1 && !decls.iter().any(|(b, _)| *b == bundle) {
+                warn!(
+                    "Reference to `{}` in `{}` is ambiguous between bundles, using `{}`",
+                    inst_name, parent_name, unit
+                );
+            }
+        }
+        self.inst_table.insert(unit.clone());
 
-        self.add_dependency_relation(&inst_name, parent_name);
+        self.add_dependency_relation(&unit, parent_name);
     }
 
     pub fn add_dependency_relation(&mut self, inst_name: &str, parent_name: &str) {
@@ -620,223 +1412,2489 @@ impl<'a> Pickle<'a> {
         id: RefNode,
         parent_name: &str,
         library_files: &mut Vec<ParsedFile>,
+        bundle: Option<usize>,
     ) {
         for node in id {
             match node {
                 RefNode::ModuleInstantiation(x) => {
                     let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+                    self.register_instantiation_with_parent(
+                        syntax_tree,
+                        id.clone(),
+                        parent_name,
+                        bundle,
+                    );
 
                     let (inst_name, _) = get_identifier(syntax_tree, id.clone());
-                    if !self.rename_table.contains_key(&inst_name) {
+                    if !self.is_declared(bundle, &inst_name) {
                         info!("Could not find {}, checking libraries...", &inst_name);
                         self.load_library_module(&inst_name, library_files);
                     }
                 }
                 RefNode::PackageImportItem(x) => {
                     let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+                    self.register_instantiation_with_parent(
+                        syntax_tree,
+                        id.clone(),
+                        parent_name,
+                        bundle,
+                    );
 
                     let (inst_name, _) = get_identifier(syntax_tree, id);
-                    if !self.rename_table.contains_key(&inst_name) {
+                    if !self.is_declared(bundle, &inst_name) {
                         info!("Could not find {}, checking libraries...", &inst_name);
                         self.load_library_module(&inst_name, library_files);
                     }
                 }
                 RefNode::PackageScope(x) => {
                     let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+                    self.register_instantiation_with_parent(
+                        syntax_tree,
+                        id.clone(),
+                        parent_name,
+                        bundle,
+                    );
 
                     let (inst_name, _) = get_identifier(syntax_tree, id);
-                    if !self.rename_table.contains_key(&inst_name) {
+                    if !self.is_declared(bundle, &inst_name) {
                         info!("Could not find {}, checking libraries...", &inst_name);
                         self.load_library_module(&inst_name, library_files);
                     }
                 }
                 RefNode::InterfacePortHeader(x) => {
                     let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+                    self.register_instantiation_with_parent(
+                        syntax_tree,
+                        id.clone(),
+                        parent_name,
+                        bundle,
+                    );
 
                     let (inst_name, _) = get_identifier(syntax_tree, id);
-                    if !self.rename_table.contains_key(&inst_name) {
+                    if !self.is_declared(bundle, &inst_name) {
                         info!("Could not find {}, checking libraries...", &inst_name);
                         self.load_library_module(&inst_name, library_files);
                     }
                 }
                 RefNode::ClassScope(x) => {
                     let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+                    self.register_instantiation_with_parent(
+                        syntax_tree,
+                        id.clone(),
+                        parent_name,
+                        bundle,
+                    );
 
                     let (inst_name, _) = get_identifier(syntax_tree, id);
-                    if !self.rename_table.contains_key(&inst_name) {
+                    if !self.is_declared(bundle, &inst_name) {
                         info!("Could not find {}, checking libraries...", &inst_name);
                         self.load_library_module(&inst_name, library_files);
                     }
                 }
+                // References to classes and configured cells, which cannot be told apart from
+                // other identifiers.
+                RefNode::ClassIdentifier(_)
+                | RefNode::NetTypeIdentifier(_)
+                | RefNode::TypeIdentifier(_)
+                | RefNode::CellIdentifier(_) => {
+                    let (name, _) = get_identifier(syntax_tree, node.clone());
+                    if self.is_declared(bundle, &name) && self.resolve(bundle, &name) != parent_name
+                    {
+                        self.register_instantiation_with_parent(
+                            syntax_tree,
+                            node,
+                            parent_name,
+                            bundle,
+                        );
+                    }
+                }
                 _ => (),
             }
         }
     }
 
-    /// Register a usage of the identifier.
-    pub fn register_usage(&mut self, syntax_tree: &SyntaxTree, id: RefNode) {
-        let (inst_name, loc) = get_identifier(syntax_tree, id);
-        let new_name = match self.rename_table.get(&inst_name) {
-            Some(x) => x,
-            None => return,
-        };
+    /// Check whether the declaration `name` in a file of `bundle` is excluded from the sources.
+    pub fn is_excluded(&mut self, bundle: Option<usize>, name: &str) -> bool {
+        let (bundles, matched) = (&self.bundles, &mut self.matched_patterns);
+        let settings = bundle.and_then(|b| bundles.get(b));
+        match_patterns(matched, EXCLUDE, &self.exclude, name)
+            | settings.is_some_and(|s| match_patterns(matched, BUNDLE_EXCLUDE, &s.exclude, name))
+    }
+
+    /// Check whether the body of the module `name` is replaced by a black box.
+    pub fn is_blackbox(&mut self, name: &str) -> bool {
+        match_patterns(&mut self.matched_patterns, BLACKBOX, &self.blackbox, name)
+    }
+
+    /// Patterns which did not match any declaration, with the option they were given with.
+    pub fn unmatched_patterns(&self) -> Vec<(&'static str, &str)> {
+        let options = vec![
+            (EXCLUDE_RENAME, &self.exclude_rename),
+            (EXCLUDE, &self.exclude),
+            (BLACKBOX, &self.blackbox),
+        ];
+        let bundles = self.bundles.iter().flat_map(|s| {
+            vec![
+                (BUNDLE_EXCLUDE_RENAME, &s.exclude_rename),
+                (BUNDLE_EXCLUDE, &s.exclude),
+            ]
+        });
+        let mut unmatched = options
+            .into_iter()
+            .chain(bundles)
+            .flat_map(|(option, patterns)| {
+                patterns.iter().map(move |p| (option, p.pattern.as_str()))
+            })
+            .filter(|(option, p)| !self.matched_patterns.contains(&(*option, p.to_string())))
+            .collect::<Vec<_>>();
+        unmatched.sort_unstable();
+        unmatched.dedup();
+        unmatched
+    }
+
+    /// Check whether a reference to `name` from a file of `bundle` refers to a declaration.
+    pub fn is_declared(&self, bundle: Option<usize>, name: &str) -> bool {
+        self.module_file_map
+            .contains_key(&self.resolve(bundle, name))
+    }
+
+    /// Register a usage of the identifier.
+    pub fn register_usage(&mut self, syntax_tree: &SyntaxTree, id: RefNode, bundle: Option<usize>) {
+        let (inst_name, loc) = get_identifier(syntax_tree, id);
+        let new_name = match self.rename_table.get(&self.resolve(bundle, &inst_name)) {
+            Some(x) => x,
+            None => return,
+        };
         debug!("Usage `{}`: {:?}", inst_name, loc);
         self.replace_table
             .push((loc.offset, loc.len, new_name.clone()));
     }
 
-    // Check whether a given declaration should be striped from the sources.
-    pub fn register_exclude(&mut self, syntax_tree: &SyntaxTree, id: RefNode, locate: Locate) {
-        let (inst_name, loc) = get_identifier(syntax_tree, id);
-        if self.exclude.contains(&inst_name) {
-            debug!("Exclude `{}`: {:?}", inst_name, loc);
+    /// Rename declarations in the given ranges of text which were not parsed, such as branches of
+    /// kept conditionals or header files. Lacking a syntax tree, only declarations, end labels,
+    /// instantiations and package scopes are recognized.
+    pub fn register_unparsed_usages(
+        &self,
+        source: &str,
+        ranges: &[(usize, usize)],
+        bundle: Option<usize>,
+    ) -> Vec<(usize, usize, String)> {
+        let ident = Regex::new(r"[a-zA-Z_][a-zA-Z0-9_$]*").unwrap();
+        let before = Regex::new(
+            r"(?:\b(?:module|macromodule|interface|program|package|checker|class|primitive|config)(?:\s+(?:static|automatic))?|\bend\w*\s*:)\s*$",
+        )
+        .unwrap();
+        let after = Regex::new(r"^(?:\s*(?:#|::)|\s+[a-zA-Z_][a-zA-Z0-9_$]*\s*[(\[])").unwrap();
+        let mut table = vec![];
+        for &(start, end) in ranges {
+            let text = &source[start..end];
+            for m in ident.find_iter(text) {
+                // Skip macros, numbers and parts of other identifiers.
+                let prev = text[..m.start()].chars().next_back();
+                if prev.is_some_and(|c| c == '`' || c == '\'' || c == '$' || c.is_alphanumeric()) {
+                    continue;
+                }
+                let new_name = match self.rename_table.get(&self.resolve(bundle, m.as_str())) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let mut context = m.start().saturating_sub(64);
+                while !text.is_char_boundary(context) {
+                    context -= 1;
+                }
+                if before.is_match(&text[context..m.start()]) || after.is_match(&text[m.end()..]) {
+                    table.push((start + m.start(), m.len(), new_name.clone()));
+                }
+            }
+        }
+        table
+    }
+
+    /// Rename the macros in the partially preprocessed text of a file, where their usages and the
+    /// conditionals on them are kept as written rather than expanded.
+    pub fn register_partial_macro_usages(
+        &self,
+        source: &str,
+        bundle: Option<usize>,
+    ) -> Vec<(usize, usize, String)> {
+        if self.macro_table.is_empty() {
+            return vec![];
+        }
+        let usage =
+            Regex::new(r"`(?:(?:define|undef|ifdef|ifndef|elsif)\s+)?([a-zA-Z_][a-zA-Z0-9_$]*)")
+                .unwrap();
+        let mut table = vec![];
+        for caps in usage.captures_iter(source) {
+            let m = caps.get(1).unwrap();
+            if let Some(new_name) = self.macro_name(bundle, m.as_str()) {
+                table.push((m.start(), m.len(), new_name.clone()));
+            }
+        }
+        table
+    }
+
+    /// Replace the text included into a file by `` `include `` directives of the header files
+    /// written with `write_headers`.
+    pub fn register_includes(
+        &mut self,
+        includes: &[Include],
+        file: &str,
+        bundle: Option<usize>,
+    ) -> Vec<(usize, usize, String)> {
+        let mut table = vec![];
+        for include in includes {
+            for header in &include.files {
+                self.register_header(header, bundle);
+                // The compilation-unit scope items declared in the header are renamed along with
+                // the including file.
+                if let Some(items) = self.unit_items.get(file) {
+                    let renamed = self.header_unit_items.entry(header.clone()).or_default();
+                    for (name, new_name) in items {
+                        renamed
+                            .entry(name.clone())
+                            .or_insert_with(|| new_name.clone());
+                    }
+                }
+            }
+            let directives: Vec<_> = include
+                .directives
+                .iter()
+                .map(|file| format!("`include \"{}\"", self.headers[file].0))
+                .collect();
+            table.push((include.offset, include.len, directives.join("\n")));
+        }
+        table
+    }
+
+    /// Split the pickled text of a file into one piece per unit, as `(unit, start, end)`. Text
+    /// between units goes with the following unit, text after the last unit with the last one.
+    pub fn split_units(
+        &self,
+        pf: &ParsedFile,
+        text: &str,
+        runs: &[(usize, usize, usize)],
+    ) -> Vec<(String, usize, usize)> {
+        let mut units = vec![];
+        for (decl, id) in unit_declarations(&pf.ast) {
+            let (start, end) = match text_range(pf, runs, node_locate(decl).unwrap()) {
+                Some(range) => range,
+                // Stripped units.
+                None => continue,
+            };
+            let (name, _) = get_identifier(&pf.ast, id);
+            units.push((self.resolve(pf.bundle, &name), start, end));
+        }
+        units.sort_by_key(|&(_, start, _)| start);
+        let mut pieces: Vec<(String, usize, usize)> = vec![];
+        for (unit, start, end) in units {
+            // Skip nested units.
+            if pieces.last().is_some_and(|&(_, _, e)| start < e) {
+                continue;
+            }
+            let prev = pieces.last().map_or(0, |&(_, _, e)| e);
+            pieces.push((unit, prev, end));
+        }
+        match pieces.last_mut() {
+            Some(last) => last.2 = text.len(),
+            None => pieces.push((pf.path.clone(), 0, text.len())),
+        }
+        pieces
+    }
+
+    /// Generate a module under the original name of a renamed top module, with the same
+    /// parameters and ports, which instantiates the renamed module.
+    ///
+    /// The declarations of the parameters and ports are taken from the emitted `text` of the
+    /// file, so the types they refer to are renamed as well.
+    pub fn top_wrapper(
+        &self,
+        pf: &ParsedFile,
+        text: &str,
+        runs: &[(usize, usize, usize)],
+        decl: RefNode,
+        id: RefNode,
+    ) -> Option<String> {
+        let st = &pf.ast;
+        let (name, loc) = get_identifier(st, id);
+        let new_name = match self.rename_table.get(&self.resolve(pf.bundle, &name)) {
+            Some(new_name) if *new_name != name => new_name,
+            _ => {
+                warn!("Top module `{}` is not renamed, skipping its wrapper", name);
+                return None;
+            }
+        };
+        let items = match blackbox_items(decl.clone()) {
+            Some(items) => items,
+            None => {
+                warn!(
+                    "Only modules can be wrapped, skipping the wrapper of `{}`",
+                    name
+                );
+                return None;
+            }
+        };
+
+        // The header following the module name, and the declarations kept in a black box.
+        let header = items[0].0.clone();
+        let header_end = node_locate(header.clone()).map(|l| l.offset + l.len)?;
+        let rest = Locate {
+            offset: loc.offset + loc.len,
+            line: loc.line,
+            len: header_end - loc.offset - loc.len,
+        };
+        let (start, end) = text_range(pf, runs, rest)?;
+        let mut wrapper = format!("module {}{}\n", name, text[start..end].trim_end());
+        for (item, _) in items.iter().skip(1).filter(|(_, keep)| *keep) {
+            if let Some((start, end)) = text_range(pf, runs, node_locate(item.clone())?) {
+                wrapper.push_str(&format!("  {}\n", text[start..end].trim()));
+            }
+        }
+
+        let params = module_parameters(st, decl)?
+            .into_iter()
+            .map(|(p, _)| (p.clone(), p))
+            .collect::<Vec<_>>();
+        let mut ports = vec![];
+        for node in header {
+            let port = match node {
+                // Explicitly named ports connect to the nets they are bound to.
+                RefNode::Port(sv_parser::Port::Named(x)) => {
+                    let (port, _) = get_identifier(st, RefNode::from(&x.nodes.1));
+                    let net = (x.nodes.2.nodes.1.as_ref())
+                        .and_then(|expr| st.get_str(expr))
+                        .unwrap_or_default();
+                    ports.push((port, net.trim().to_string()));
+                    continue;
+                }
+                RefNode::Port(sv_parser::Port::NonNamed(x)) => match &x.nodes.0 {
+                    Some(sv_parser::PortExpression::PortReference(x)) => RefNode::from(&x.nodes.0),
+                    Some(_) => {
+                        warn!(
+                            "Top module `{}` has a port which cannot be connected by name, \
+                             skipping its wrapper",
+                            name
+                        );
+                        return None;
+                    }
+                    None => continue,
+                },
+                RefNode::AnsiPortDeclaration(x) => unwrap_node!(x, PortIdentifier)?,
+                _ => continue,
+            };
+            let (port, _) = get_identifier(st, port);
+            ports.push((port.clone(), port));
+        }
+
+        let connections = |names: &[(String, String)]| {
+            names
+                .iter()
+                .map(|(n, v)| format!("\n    .{}({})", n, v))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        wrapper.push_str(&format!("  {}", new_name));
+        if !params.is_empty() {
+            wrapper.push_str(&format!(" #({}\n  )", connections(&params)));
+        }
+        wrapper.push_str(&format!(" i_{} (", new_name));
+        if !ports.is_empty() {
+            wrapper.push_str(&format!("{}\n  ", connections(&ports)));
+        }
+        wrapper.push_str(");\nendmodule\n");
+        eprintln!("Wrapping `{}` in a module named `{}`", new_name, name);
+        Some(wrapper)
+    }
+
+    /// Check that the parameters of all overrides are declared by a module, and that no parameter
+    /// is overridden twice.
+    pub fn check_param_overrides(&self, files: &[ParsedFile]) -> Result<()> {
+        let mut seen = HashSet::new();
+        for o in &self.param_overrides {
+            if !seen.insert((&o.module, &o.name)) {
+                return Err(anyhow!(
+                    "Parameter `{}.{}` is overridden more than once",
+                    o.module,
+                    o.name
+                ));
+            }
+            let mut declared = None;
+            let mut locals = vec![];
+            for pf in files {
+                for (decl, id) in unit_declarations(&pf.ast) {
+                    if get_identifier(&pf.ast, id).0 != o.module {
+                        continue;
+                    }
+                    if let Some(params) = module_parameters(&pf.ast, decl.clone()) {
+                        let names = declared.get_or_insert_with(Vec::new);
+                        names.extend(params.into_iter().map(|(name, _)| name));
+                        locals.extend(module_localparams(&pf.ast, decl));
+                    }
+                }
+            }
+            match declared {
+                None => {
+                    return Err(anyhow!(
+                        "Module `{}` not found for `--param {}`",
+                        o.module,
+                        o
+                    ))
+                }
+                Some(names) if !names.contains(&o.name) && locals.contains(&o.name) => {
+                    return Err(anyhow!(
+                        "`{}` is a localparam of `{}` and cannot be overridden",
+                        o.name,
+                        o.module
+                    ))
+                }
+                Some(names) if !names.contains(&o.name) => {
+                    return Err(anyhow!(
+                        "Module `{}` has no parameter `{}`, found: {}",
+                        o.module,
+                        o.name,
+                        names.join(", ")
+                    ))
+                }
+                Some(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the default values of the overridden parameters of the modules in a file.
+    pub fn register_param_overrides(&mut self, syntax_tree: &SyntaxTree) {
+        if self.param_overrides.is_empty() {
+            return;
+        }
+        for (decl, id) in unit_declarations(syntax_tree) {
+            let (module, _) = get_identifier(syntax_tree, id);
+            let params = match module_parameters(syntax_tree, decl) {
+                Some(params) => params,
+                None => continue,
+            };
+            for o in self.param_overrides.iter().filter(|o| o.module == module) {
+                let assignment = match params.iter().find(|(name, _)| *name == o.name) {
+                    Some((_, assignment)) => assignment,
+                    None => continue,
+                };
+                let default = match assignment {
+                    RefNode::ParamAssignment(x) => {
+                        x.nodes.2.as_ref().map(|(_, expr)| RefNode::from(expr))
+                    }
+                    RefNode::TypeAssignment(x) => {
+                        x.nodes.1.as_ref().map(|(_, ty)| RefNode::from(ty))
+                    }
+                    _ => continue,
+                };
+                debug!("Override `{}`", o);
+                match default {
+                    Some(node) => {
+                        let loc = node_locate(node).unwrap();
+                        let len = syntax_tree.get_str(&loc).unwrap().trim_end().len();
+                        self.replace_table.push((loc.offset, len, o.value.clone()));
+                    }
+                    // Parameters without a default get one.
+                    None => {
+                        let loc = node_locate(assignment.clone()).unwrap();
+                        let len = syntax_tree.get_str(&loc).unwrap().trim_end().len();
+                        self.replace_table
+                            .push((loc.offset + len, 0, format!(" = {}", o.value)));
+                    }
+                }
+            }
+        }
+    }
+
+    // Assign a unique name in the include directory to a header file.
+    fn register_header(&mut self, path: &Path, bundle: Option<usize>) {
+        if self.headers.contains_key(path) {
+            return;
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let unique = unique_file_name(&name, |n| self.headers.values().any(|(h, _)| h == n));
+        self.headers.insert(path.to_path_buf(), (unique, bundle));
+    }
+
+    /// Write the included header files to a directory, with their includes pointing to each
+    /// other and the declarations renamed.
+    pub fn write_headers(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create `{}`", dir.display()))?;
+        let directive = Regex::new(r#"`include\s*(?:"([^"]*)"|<([^>]*)>)"#).unwrap();
+        let ident = Regex::new(r"[a-zA-Z_][a-zA-Z0-9_$]*").unwrap();
+        for (path, (name, bundle)) in &self.headers {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read `{}`", path.display()))?;
+            let mut table = self.register_unparsed_usages(&text, &[(0, text.len())], *bundle);
+            if let Some(items) = self.header_unit_items.get(path) {
+                for m in ident.find_iter(&text) {
+                    // Skip macros, numbers, named assignments and parts of other identifiers.
+                    let prev = text[..m.start()].chars().next_back();
+                    if prev.is_some_and(|c| "`'$.".contains(c) || c.is_alphanumeric()) {
+                        continue;
+                    }
+                    if let Some(new_name) = items.get(m.as_str()) {
+                        table.push((m.start(), m.len(), new_name.clone()));
+                    }
+                }
+            }
+            for caps in directive.captures_iter(&text) {
+                let (m, file) = (caps.get(0).unwrap(), caps.get(1).or(caps.get(2)).unwrap());
+                match self
+                    .headers
+                    .iter()
+                    .find(|(p, _)| p.ends_with(file.as_str()))
+                {
+                    Some((_, (new, _))) => {
+                        table.push((m.start(), m.len(), format!("`include \"{}\"", new)))
+                    }
+                    None => warn!(
+                        "Keeping `{}` included in {} as it was never included",
+                        file.as_str(),
+                        path.display()
+                    ),
+                }
+            }
+            table.sort_by_key(|a| a.0);
+            let mut out = String::new();
+            let mut pos = 0;
+            for (offset, len, repl) in table {
+                if pos > offset {
+                    continue;
+                }
+                out.push_str(&text[pos..offset]);
+                out.push_str(&repl);
+                pos = offset + len;
+            }
+            out.push_str(&text[pos..]);
+            info!("Writing `{}` to `{}`", path.display(), name);
+            std::fs::write(dir.join(name), out)
+                .with_context(|| format!("Failed to write `{}`", dir.join(name).display()))?;
+        }
+        Ok(())
+    }
+
+    /// Register the typedefs, functions, tasks and parameters declared in the compilation-unit
+    /// scope of a file for renaming within this file.
+    pub fn register_unit_items(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        file: &str,
+        bundle: Option<usize>,
+    ) {
+        let items = unit_items(syntax_tree);
+        if items.is_empty() {
+            return;
+        }
+        let mut report = vec![];
+        let mut table = HashMap::new();
+        for (kind, id) in items {
+            let (name, _) = get_identifier(syntax_tree, id);
+            report.push(format!("{} `{}`", kind, name));
+            if let Some(new_name) = self.new_name(bundle, &name) {
+                table.insert(name, new_name);
+            }
+        }
+        warn!(
+            "Compilation-unit scope declarations in {}:\n\t{}",
+            file,
+            report.join("\n\t")
+        );
+        self.unit_items.insert(file.to_string(), table);
+    }
+
+    /// Rename the compilation-unit scope items of a file where they are declared and used. Names
+    /// of named parameter and port assignments are left alone, as are references within scopes
+    /// that declare an item of the same name.
+    pub fn register_unit_item_usages(&mut self, syntax_tree: &SyntaxTree, file: &str) {
+        let table = match self.unit_items.get(file) {
+            Some(table) if !table.is_empty() => table,
+            _ => return,
+        };
+        let named = named_assignments(syntax_tree);
+        let mut scopes: Vec<(RefNode, HashSet<String>)> = vec![];
+        for event in syntax_tree.into_iter().event() {
+            let node = match event {
+                NodeEvent::Enter(node) => node,
+                NodeEvent::Leave(node) => {
+                    if is_scope(&node) {
+                        scopes.pop();
+                    }
+                    continue;
+                }
+            };
+            if is_scope(&node) {
+                let declared = scope_declarations(syntax_tree, node.clone(), &named)
+                    .into_iter()
+                    .filter(|name| table.contains_key(name))
+                    .collect();
+                scopes.push((node, declared));
+                continue;
+            }
+            let id = match node {
+                RefNode::TypeIdentifier(_)
+                | RefNode::ClassIdentifier(_)
+                | RefNode::NetTypeIdentifier(_)
+                | RefNode::TfIdentifier(_)
+                | RefNode::FunctionIdentifier(_)
+                | RefNode::TaskIdentifier(_)
+                | RefNode::ParameterIdentifier(_) => node,
+                // Only plain names, not hierarchical references.
+                RefNode::HierarchicalIdentifier(x)
+                    if x.nodes.0.is_none() && x.nodes.1.is_empty() =>
+                {
+                    RefNode::from(&x.nodes.2)
+                }
+                _ => continue,
+            };
+            let (name, loc) = get_identifier(syntax_tree, id);
+            if named.contains(&loc.offset) {
+                continue;
+            }
+            if scopes.iter().any(|(_, declared)| declared.contains(&name)) {
+                debug!("`{}` shadows a compilation-unit item: {:?}", name, loc);
+                continue;
+            }
+            if let Some(new_name) = table.get(&name) {
+                debug!("Compilation-unit item `{}`: {:?}", name, loc);
+                self.replace_table
+                    .push((loc.offset, loc.len, new_name.clone()));
+            }
+        }
+    }
+
+    /// Register the macros defined in a file for renaming with the prefix and suffix, unless they
+    /// match one of the `external` patterns.
+    pub fn register_macro_definitions(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        bundle: Option<usize>,
+        external: &[NamePattern],
+    ) {
+        for node in syntax_tree {
+            if let RefNode::TextMacroDefinition(x) = node {
+                let id = unwrap_node!(x, TextMacroIdentifier).unwrap();
+                let (name, _) = get_identifier(syntax_tree, id);
+                if external.iter().any(|p| p.is_match(&name)) {
+                    continue;
+                }
+                let new_name = self
+                    .affixed_name(bundle, &name)
+                    .unwrap_or_else(|| name.clone());
+                let defs = self.macro_table.entry(name.clone()).or_default();
+                if !defs.iter().any(|(b, _)| *b == bundle) {
+                    debug!("Macro `{}` -> `{}`", name, new_name);
+                    defs.push((bundle, new_name));
+                }
+            }
+        }
+    }
+
+    /// Rename the macros in `define and `undef directives as well as within macro texts.
+    pub fn register_macro_usages(&mut self, syntax_tree: &SyntaxTree, bundle: Option<usize>) {
+        if self.macro_table.is_empty() {
+            return;
+        }
+        // Macro usages and conditionals within the (unparsed) text of other macros.
+        let usage =
+            Regex::new(r"`(?:(ifdef|ifndef|elsif|undef)(\s+))?([a-zA-Z_][a-zA-Z0-9_$]*)").unwrap();
+        for node in syntax_tree {
+            match node {
+                RefNode::TextMacroIdentifier(x) => {
+                    let (name, loc) = get_identifier(syntax_tree, RefNode::TextMacroIdentifier(x));
+                    if let Some(new_name) = self.macro_name(bundle, &name) {
+                        self.replace_table
+                            .push((loc.offset, loc.len, new_name.clone()));
+                    }
+                }
+                RefNode::MacroText(x) => {
+                    let loc = x.nodes.0;
+                    let text = syntax_tree.get_str(&loc).unwrap();
+                    let renamed = usage.replace_all(text, |caps: &regex::Captures| {
+                        let name = &caps[3];
+                        let directive = caps.get(1).map_or("", |m| m.as_str());
+                        let space = caps.get(2).map_or("", |m| m.as_str());
+                        let new_name = self.macro_name(bundle, name).map_or(name, |n| n.as_str());
+                        if directive.is_empty() {
+                            format!("`{}", new_name)
+                        } else {
+                            format!("`{}{}{}", directive, space, new_name)
+                        }
+                    });
+                    if renamed != text {
+                        self.replace_table
+                            .push((loc.offset, loc.len, renamed.into_owned()));
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Rename the root of a hierarchical reference, such as `tb_top.i_dut.sig` or
+    /// `$root.tb_top.sig`, if it refers to a renamed unit rather than a local name.
+    pub fn register_hierarchical_usage(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        x: &sv_parser::HierarchicalIdentifier,
+        bundle: Option<usize>,
+        local_names: &HashSet<String>,
+    ) {
+        // Plain names are not hierarchical references.
+        if x.nodes.0.is_none() && x.nodes.1.is_empty() {
+            return;
+        }
+        let (name, loc) = get_identifier(syntax_tree, hierarchical_root(x));
+        if x.nodes.0.is_none() && local_names.contains(&name) {
+            return;
+        }
+        if let Some(new_name) = self.rename_table.get(&self.resolve(bundle, &name)) {
+            debug!("Hierarchical reference `{}`: {:?}", name, loc);
             self.replace_table
-                .push((locate.offset, locate.len, "".to_string()));
+                .push((loc.offset, loc.len, new_name.clone()));
         }
     }
 
-    // Load the module with name 'module_name' and append the resulting ParsedFile to 'files'.
-    // This function may recursively load other modules if the library uses another library module.
-    // If no module is found in the library bundle, this function does nothing.
-    pub fn load_library_module(&mut self, module_name: &str, files: &mut Vec<ParsedFile>) {
-        let rm = self.libs.load_module(module_name, &mut self.used_libs);
-        match rm {
-            Ok(pf) => {
-                // register all declarations from this library file.
-                for node in &pf.ast {
-                    match node {
-                        RefNode::ModuleDeclarationAnsi(x) => {
-                            let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                            self.register_declaration(&pf.ast, id, pf.path.clone());
+    /// Add the units bound into other units by bind directives to the dependency graph, both in
+    /// the compilation-unit scope and in the bodies of units.
+    pub fn register_binds(&mut self, syntax_tree: &SyntaxTree, bundle: Option<usize>) {
+        for node in syntax_tree {
+            let (targets, bound) = match node {
+                RefNode::BindDirective(x) => bind_targets(x),
+                _ => continue,
+            };
+            let (bound, _) = get_identifier(syntax_tree, bound);
+            let bound = self.resolve(bundle, &bound);
+            if !self.module_file_map.contains_key(&bound) {
+                continue;
+            }
+            for target in targets {
+                let (target, _) = get_identifier(syntax_tree, target);
+                let target = self.resolve(bundle, &target);
+                if self.module_file_map.contains_key(&target) {
+                    self.add_dependency_relation(&bound, &target);
+                }
+            }
+        }
+    }
+
+    /// Check whether any bind directive of a file binds into a unit which is not pruned, and
+    /// strip the ones which do not.
+    pub fn register_bind_exclude(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        bundle: Option<usize>,
+    ) -> bool {
+        let mut kept = false;
+        for (directive, targets, _) in bind_directives(syntax_tree) {
+            let pruned = targets.into_iter().all(|target| {
+                let (target, _) = get_identifier(syntax_tree, target);
+                self.pruned.contains(&self.resolve(bundle, &target))
+            });
+            if pruned {
+                let loc = node_locate(directive).unwrap();
+                debug!("Exclude bind: {:?}", loc);
+                self.replace_table
+                    .push((loc.offset, loc.len, "".to_string()));
+            } else {
+                kept = true;
+            }
+        }
+        kept
+    }
+
+    /// Move the package imports in the compilation-unit scope of a file into the headers of the
+    /// modules, interfaces, programs and packages declared after them. Imports are left in place
+    /// if other compilation-unit scope items might depend on them.
+    pub fn register_global_imports(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        file: &str,
+        bundle: Option<usize>,
+    ) {
+        use sv_parser::{
+            DataDeclaration, Description, PackageItem, PackageOrGenerateItemDeclaration,
+        };
+        let mut imports: Vec<String> = vec![];
+        let mut removals = vec![];
+        let mut insertions = vec![];
+        for node in syntax_tree {
+            let description = match node {
+                RefNode::Description(x) => x,
+                _ => continue,
+            };
+            let target = match description {
+                Description::PackageItem(x) => match &x.nodes.1 {
+                    PackageItem::PackageOrGenerateItemDeclaration(x) => match &**x {
+                        PackageOrGenerateItemDeclaration::DataDeclaration(x) => match &**x {
+                            DataDeclaration::PackageImportDeclaration(x) => {
+                                imports.push(self.renamed_import(syntax_tree, x, bundle));
+                                removals.push(node_locate(node.clone()).unwrap());
+                                continue;
+                            }
+                            _ => None,
+                        },
+                        PackageOrGenerateItemDeclaration::Empty(_) => continue,
+                        _ => None,
+                    },
+                    PackageItem::TimeunitsDeclaration(_) => continue,
+                    _ => None,
+                },
+                Description::ModuleDeclaration(_)
+                | Description::InterfaceDeclaration(_)
+                | Description::ProgramDeclaration(_) => {
+                    let id = unwrap_node!(
+                        node.clone(),
+                        ModuleIdentifier,
+                        InterfaceIdentifier,
+                        ProgramIdentifier
+                    );
+                    let (_, loc) = get_identifier(syntax_tree, id.unwrap());
+                    Some((loc.offset + loc.len, " "))
+                }
+                Description::PackageDeclaration(x) => {
+                    let loc = x.nodes.4.nodes.0;
+                    Some((loc.offset + loc.len, "\n  "))
+                }
+                Description::UdpDeclaration(_)
+                | Description::ConfigDeclaration(_)
+                | Description::BindDirective(_)
+                | Description::ResetallCompilerDirective(_) => continue,
+                Description::InterfaceClassDeclaration(_) => None,
+            };
+            if imports.is_empty() {
+                continue;
+            }
+            match target {
+                Some((offset, separator)) => {
+                    let text = imports
+                        .iter()
+                        .map(|i| format!("{}{}", separator, i))
+                        .collect::<String>();
+                    insertions.push((offset, 0, text));
+                }
+                None => {
+                    warn!(
+                        "Global package import in {} is kept, as compilation-unit scope items might depend on it",
+                        file
+                    );
+                    return;
+                }
+            }
+        }
+        for loc in removals {
+            self.replace_table
+                .push((loc.offset, loc.len, "".to_string()));
+        }
+        self.replace_table.extend(insertions);
+    }
+
+    /// Format a package import declaration with the renamed packages.
+    fn renamed_import(
+        &self,
+        syntax_tree: &SyntaxTree,
+        import: &sv_parser::PackageImportDeclaration,
+        bundle: Option<usize>,
+    ) -> String {
+        use sv_parser::PackageImportItem;
+        let items = import
+            .nodes
+            .1
+            .contents()
+            .into_iter()
+            .map(|item| {
+                let (package, item) = match item {
+                    PackageImportItem::Identifier(x) => (
+                        &x.nodes.0,
+                        get_identifier(syntax_tree, RefNode::from(&x.nodes.2)).0,
+                    ),
+                    PackageImportItem::Asterisk(x) => (&x.nodes.0, "*".to_string()),
+                };
+                let (package, _) = get_identifier(syntax_tree, RefNode::from(package));
+                let package = self
+                    .rename_table
+                    .get(&self.resolve(bundle, &package))
+                    .unwrap_or(&package);
+                format!("{}::{}", package, item)
+            })
+            .collect::<Vec<_>>();
+        format!("import {};", items.join(", "))
+    }
+
+    /// Strip the regions between translate_off and translate_on markers, such as
+    /// `// synopsys translate_off` or `/* pragma translate_off */`, from a file.
+    pub fn register_translate_off(&mut self, pf: &ParsedFile) {
+        let syntax_tree = &pf.ast;
+        let marker = Regex::new(
+            r"^(?://|/\*)\s*(?:(?:synopsys|synthesis|pragma|cadence|xilinx|altera)\s+(?:translate|synthesis)_|rtl_synthesis\s+)(off|on)\b",
+        )
+        .unwrap();
+        let mut start: Option<Locate> = None;
+        for node in syntax_tree {
+            let loc = match node {
+                RefNode::Comment(x) => x.nodes.0,
+                _ => continue,
+            };
+            let text = syntax_tree.get_str(&loc).unwrap();
+            let off = match marker.captures(text) {
+                Some(caps) => &caps[1] == "off",
+                None => continue,
+            };
+            // Markers do not nest, the region ends at the first `translate_on`.
+            match (off, start) {
+                (true, None) => start = Some(loc),
+                (true, Some(start)) => {
+                    let (path, line) = pf.source_line(loc.offset);
+                    warn!(
+                        "Repeated `{}` in {}:{}, the region started at line {}",
+                        text.trim(),
+                        path,
+                        line,
+                        pf.source_line(start.offset).1
+                    )
+                }
+                (false, None) => {
+                    let (path, line) = pf.source_line(loc.offset);
+                    warn!("Unmatched `{}` in {}:{}", text.trim(), path, line)
+                }
+                (false, Some(region)) => {
+                    self.strip_region(pf, region.offset, loc.offset + loc.len);
+                    start = None;
+                }
+            }
+        }
+        if let Some(start) = start {
+            let (path, line) = pf.source_line(start.offset);
+            warn!(
+                "Unterminated translate_off in {}:{}, stripping until the end of the file",
+                path, line
+            );
+            self.strip_region(pf, start.offset, pf.source.len());
+        }
+    }
+
+    // Strip the text from `start` to `end`, from the start of the `translate_off` marker to the
+    // end of the `translate_on` marker.
+    fn strip_region(&mut self, pf: &ParsedFile, start: usize, end: usize) {
+        let (path, first) = pf.source_line(start);
+        let (_, last) = pf.source_line(end.saturating_sub(1));
+        eprintln!(
+            "Stripping translate_off region in {}:{}-{}",
+            path, first, last
+        );
+        self.replace_table
+            .push((start, end - start, "".to_string()));
+    }
+
+    /// Strip concurrent assertions, assumptions and cover statements, property and sequence
+    /// declarations and covergroups (with their instances and uses) from a file, as well as
+    /// immediate assertions if `immediate` is set.
+    pub fn register_assertions(&mut self, syntax_tree: &SyntaxTree, file: &str, immediate: bool) {
+        use sv_parser::{
+            ConcurrentAssertionItem, ConcurrentAssertionStatement, ProceduralAssertionStatement,
+            StatementItem,
+        };
+        let concurrent_kind = |x: &ConcurrentAssertionStatement| match x {
+            ConcurrentAssertionStatement::AssertPropertyStatement(_) => "assert",
+            ConcurrentAssertionStatement::AssumePropertyStatement(_) => "assume",
+            ConcurrentAssertionStatement::CoverPropertyStatement(_)
+            | ConcurrentAssertionStatement::CoverSequenceStatement(_) => "cover",
+            ConcurrentAssertionStatement::RestrictPropertyStatement(_) => "restrict",
+        };
+
+        // Covergroups and their instances, whose uses are stripped as well.
+        let mut covergroups = HashSet::new();
+        for node in syntax_tree {
+            if let RefNode::CovergroupDeclaration(x) = node {
+                covergroups.insert(get_identifier(syntax_tree, RefNode::from(&x.nodes.1)).0);
+            }
+        }
+        let units = unit_declarations(syntax_tree)
+            .into_iter()
+            .map(|(decl, id)| {
+                (
+                    node_locate(decl).unwrap(),
+                    get_identifier(syntax_tree, id).0,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut counts: BTreeMap<String, BTreeMap<&str, usize>> = BTreeMap::new();
+        let mut end = 0;
+        for node in syntax_tree {
+            // Items are removed, statements are replaced by an empty block.
+            let (kind, replacement) = match &node {
+                RefNode::PropertyDeclaration(_) => ("property", ""),
+                RefNode::SequenceDeclaration(_) => ("sequence", ""),
+                RefNode::CovergroupDeclaration(_) => ("covergroup", ""),
+                RefNode::ConcurrentAssertionItem(ConcurrentAssertionItem::Statement(x)) => {
+                    (concurrent_kind(&x.nodes.1), "")
+                }
+                RefNode::DeferredImmediateAssertionItem(_) if immediate => {
+                    ("immediate assertion", "")
+                }
+                RefNode::DataDeclaration(_) | RefNode::NetDeclaration(_) => {
+                    let ty = unwrap_node!(
+                        node.clone(),
+                        ClassIdentifier,
+                        NetTypeIdentifier,
+                        TypeIdentifier
+                    );
+                    let ty = ty.map(|ty| get_identifier(syntax_tree, ty).0);
+                    match ty {
+                        Some(ty) if covergroups.contains(&ty) => ("covergroup instance", ""),
+                        _ => continue,
+                    }
+                }
+                RefNode::Statement(x) => match &x.nodes.2 {
+                    StatementItem::ProceduralAssertionStatement(x) => match &**x {
+                        ProceduralAssertionStatement::Concurrent(x) => {
+                            (concurrent_kind(x), "begin end")
                         }
-                        RefNode::ModuleDeclarationNonansi(x) => {
-                            let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                            self.register_declaration(&pf.ast, id, pf.path.clone());
+                        ProceduralAssertionStatement::Immediate(_) if immediate => {
+                            ("immediate assertion", "begin end")
+                        }
+                        _ => continue,
+                    },
+                    // Sampling and construction of covergroups.
+                    StatementItem::SubroutineCallStatement(_)
+                    | StatementItem::BlockingAssignment(_) => {
+                        match unwrap_node!(node.clone(), HierarchicalIdentifier) {
+                            Some(RefNode::HierarchicalIdentifier(x))
+                                if covergroups.contains(
+                                    &get_identifier(syntax_tree, hierarchical_root(x)).0,
+                                ) =>
+                            {
+                                ("covergroup use", "begin end")
+                            }
+                            _ => continue,
                         }
-                        _ => (),
                     }
-                }
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let mut loc = node_locate(node.clone()).unwrap();
+            // Skip nested constructs of stripped ones.
+            if loc.offset < end {
+                continue;
+            }
+            end = loc.offset + loc.len;
+            if kind == "covergroup instance" {
+                for node in node.clone() {
+                    if let RefNode::VariableIdentifier(_) | RefNode::NetIdentifier(_) = node {
+                        covergroups.insert(get_identifier(syntax_tree, node).0);
+                    }
+                }
+            }
+            // Keep the whitespace following a stripped construct.
+            loc.len = syntax_tree.get_str(&loc).unwrap().trim_end().len();
+            // Removed items take the indentation and the line break preceding them along, so the
+            // following text stays on its line.
+            if replacement.is_empty() {
+                let char_at = |offset| {
+                    let loc = Locate {
+                        offset,
+                        line: 0,
+                        len: 1,
+                    };
+                    syntax_tree.get_str(&loc).unwrap_or_default()
+                };
+                let mut start = loc.offset;
+                while start > 0 && matches!(char_at(start - 1), " " | "\t") {
+                    start -= 1;
+                }
+                if start > 0 && char_at(start - 1) == "\n" {
+                    start -= 1;
+                    if start > 0 && char_at(start - 1) == "\r" {
+                        start -= 1;
+                    }
+                    loc.len += loc.offset - start;
+                    loc.offset = start;
+                }
+            }
+            self.replace_table
+                .push((loc.offset, loc.len, replacement.to_string()));
+            let unit = units
+                .iter()
+                .find(|(l, _)| l.offset <= loc.offset && loc.offset < l.offset + l.len)
+                .map_or("$unit", |(_, name)| name.as_str());
+            *counts
+                .entry(unit.to_string())
+                .or_default()
+                .entry(kind)
+                .or_default() += 1;
+        }
+        for (unit, kinds) in counts {
+            let kinds = kinds
+                .iter()
+                .map(|(kind, count)| format!("{} {}", count, kind))
+                .collect::<Vec<_>>();
+            eprintln!("Stripped from `{}` in {}: {}", unit, file, kinds.join(", "));
+        }
+    }
+
+    /// Report declarations with the same name and resolve them according to `policy`.
+    pub fn resolve_duplicates(&mut self, policy: DuplicatePolicy) -> Result<()> {
+        // A file listed multiple times declares the same units, which are not duplicates.
+        for sites in self.declaration_sites.values_mut() {
+            let mut seen = HashSet::new();
+            sites.retain(|site| seen.insert(site.clone()));
+        }
+        let mut duplicates = self
+            .declaration_sites
+            .iter()
+            .filter(|(_, sites)| sites.len() > 1)
+            .map(|(name, sites)| (name.clone(), sites.clone()))
+            .collect::<Vec<_>>();
+        if duplicates.is_empty() {
+            return Ok(());
+        }
+        duplicates.sort();
+
+        let report = duplicates
+            .iter()
+            .map(|(name, sites)| {
+                let files = sites.iter().map(|(f, _)| f.as_str()).collect::<Vec<_>>();
+                format!("`{}` in {}", name, files.join(", "))
+            })
+            .collect::<Vec<_>>()
+            .join("\n\t");
+
+        match policy {
+            DuplicatePolicy::Error => {
+                return Err(anyhow!("Duplicate declarations:\n\t{}", report));
+            }
+            DuplicatePolicy::Warn => {
+                warn!("Duplicate declarations:\n\t{}", report);
+                return Ok(());
+            }
+            DuplicatePolicy::KeepFirst => {
+                warn!("Duplicate declarations, keeping the first:\n\t{}", report)
+            }
+            DuplicatePolicy::KeepLast => {
+                warn!("Duplicate declarations, keeping the last:\n\t{}", report)
+            }
+        }
+
+        for (name, mut sites) in duplicates {
+            let kept = match policy {
+                DuplicatePolicy::KeepFirst => sites.remove(0),
+                _ => sites.pop().unwrap(),
+            };
+            self.dropped_declarations.extend(sites);
+            self.module_file_map.insert(name, kept.0);
+        }
+        Ok(())
+    }
+
+    /// Drop the declarations of a substituted module and check that the ports of its substitute
+    /// match. Returns the index of the last file declaring the module.
+    pub fn register_substitution(
+        &mut self,
+        files: &[ParsedFile],
+        substitute: &ParsedFile,
+        module: &str,
+    ) -> Result<usize> {
+        fn declaration<'a>(pf: &'a ParsedFile, module: &str) -> Option<(RefNode<'a>, RefNode<'a>)> {
+            unit_declarations(&pf.ast).into_iter().find(|(decl, id)| {
+                blackbox_items(decl.clone()).is_some()
+                    && get_identifier(&pf.ast, id.clone()).0 == module
+            })
+        }
+        let (decl, _) = declaration(substitute, module).unwrap();
+        let ports = module_ports(&substitute.ast, decl.clone()).unwrap();
+        let params = parameter_names(&substitute.ast, decl);
+
+        let mut index = None;
+        for (i, pf) in files.iter().enumerate() {
+            let (decl, id) = match declaration(pf, module) {
+                Some(x) => x,
+                None => continue,
+            };
+            let original = module_ports(&pf.ast, decl.clone()).unwrap();
+            let original_params = parameter_names(&pf.ast, decl);
+            let find = |ports: &[(String, String, String)], name: &str, dir: &str| {
+                ports
+                    .iter()
+                    .find(|(n, d, _)| n == name && d == dir)
+                    .map(|(_, _, ty)| ty.clone())
+            };
+            let mut mismatches = vec![];
+            for (name, dir, ty) in &original {
+                match find(&ports, name, dir) {
+                    None => mismatches.push(format!("missing {} `{}`", dir, name)),
+                    Some(new) if new != *ty => mismatches.push(format!(
+                        "{} `{}` is `{}` instead of `{}`",
+                        dir, name, new, ty
+                    )),
+                    Some(_) => (),
+                }
+            }
+            for (name, dir, _) in &ports {
+                if find(&original, name, dir).is_none() {
+                    mismatches.push(format!("additional {} `{}`", dir, name));
+                }
+            }
+            for name in original_params.iter().filter(|p| !params.contains(p)) {
+                mismatches.push(format!("missing parameter `{}`", name));
+            }
+            for name in params.iter().filter(|p| !original_params.contains(p)) {
+                mismatches.push(format!("additional parameter `{}`", name));
+            }
+            if !mismatches.is_empty() {
+                return Err(anyhow!(
+                    "Ports or parameters of the substitute of `{}` in {} do not match the ones in \
+                     {}:\n\t{}",
+                    module,
+                    substitute.path,
+                    pf.path,
+                    mismatches.join("\n\t")
+                ));
+            }
+            let (_, loc) = get_identifier(&pf.ast, id);
+            self.dropped_declarations
+                .insert((pf.path.clone(), loc.offset));
+            index = Some(i);
+        }
+        let index =
+            index.ok_or_else(|| anyhow!("Module `{}` to be substituted not found", module))?;
+        eprintln!(
+            "Substituting `{}` in {} with {}",
+            module, files[index].path, substitute.path
+        );
+        Ok(index)
+    }
+
+    /// Check whether the declaration at `loc` in `file` is a dropped duplicate.
+    pub fn is_dropped(&self, file: &str, loc: &Locate) -> bool {
+        self.dropped_declarations
+            .contains(&(file.to_string(), loc.offset))
+    }
+
+    // Check whether a given declaration should be striped from the sources.
+    pub fn register_exclude(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        id: RefNode,
+        locate: Locate,
+        file: &str,
+        bundle: Option<usize>,
+    ) {
+        let (inst_name, loc) = get_identifier(syntax_tree, id);
+        if self.is_excluded(bundle, &inst_name)
+            || self.pruned.contains(&self.resolve(bundle, &inst_name))
+            || self.is_dropped(file, &loc)
+        {
+            debug!("Exclude `{}`: {:?}", inst_name, loc);
+            self.replace_table
+                .push((locate.offset, locate.len, "".to_string()));
+        }
+    }
+
+    /// Strip the body of a black-boxed module, keeping only its parameter, port and type
+    /// declarations and package imports.
+    pub fn register_blackbox(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        decl: RefNode,
+        id: RefNode,
+        file: &str,
+        bundle: Option<usize>,
+    ) {
+        let (name, loc) = get_identifier(syntax_tree, id);
+        if !self.is_blackbox(&name)
+            || self.is_excluded(bundle, &name)
+            || self.pruned.contains(&self.resolve(bundle, &name))
+            || self.is_dropped(file, &loc)
+        {
+            return;
+        }
+        let items = match blackbox_items(decl) {
+            Some(items) => items,
+            None => {
+                info!(
+                    "Only modules can be black-boxed, keeping `{}` in {}",
+                    name, file
+                );
+                return;
+            }
+        };
+        eprintln!(
+            "Replacing the body of `{}` in {} with a black box",
+            name, file
+        );
+        // Each stripped item is removed along with the whitespace preceding it, so the kept
+        // items stay on their lines.
+        let mut end = 0;
+        for (item, keep) in items {
+            let loc = match node_locate(item) {
+                Some(loc) => loc,
+                None => continue,
+            };
+            let item_end = loc.offset + syntax_tree.get_str(&loc).unwrap().trim_end().len();
+            if !keep && end > 0 {
+                self.replace_table
+                    .push((end, item_end - end, "".to_string()));
+            }
+            end = item_end;
+        }
+    }
+
+    // Load the module with name 'module_name' and append the resulting ParsedFile to 'files'.
+    // This function may recursively load other modules if the library uses another library module.
+    // If no module is found in the library bundle, this function does nothing.
+    pub fn load_library_module(&mut self, module_name: &str, files: &mut Vec<ParsedFile>) {
+        let rm = self.libs.load_module(module_name, &mut self.used_libs);
+        match rm {
+            Ok(pf) => {
+                // register all declarations from this library file.
+                for (_, id) in unit_declarations(&pf.ast) {
+                    self.register_declaration(&pf.ast, id, pf.path.clone(), None);
+                }
                 // look for all module instantiations
                 for node in &pf.ast {
                     if let RefNode::ModuleInstantiation(x) = node {
                         let id = unwrap_node!(x, SimpleIdentifier).unwrap();
                         self.register_instantiation(&pf.ast, id.clone());
 
-                        // if this module is undefined, recursively attempt to load a library
-                        // module for it.
-                        let (inst_name, _) = get_identifier(&pf.ast, id);
-                        info!(
-                            "Instantiation `{}` in library module `{}`",
-                            &inst_name, &module_name
-                        );
-                        if !self.rename_table.contains_key(&inst_name) {
-                            info!("load library module {}", &inst_name);
-                            self.load_library_module(&inst_name, files);
-                        }
+                        // if this module is undefined, recursively attempt to load a library
+                        // module for it.
+                        let (inst_name, _) = get_identifier(&pf.ast, id);
+                        info!(
+                            "Instantiation `{}` in library module `{}`",
+                            &inst_name, &module_name
+                        );
+                        if !self.is_declared(None, &inst_name) {
+                            info!("load library module {}", &inst_name);
+                            self.load_library_module(&inst_name, files);
+                        }
+                    }
+                }
+                // add the parsed file to the vector.
+                files.push(pf);
+            }
+            Err(e) => info!("error loading library: {}", e),
+        }
+    }
+
+    /// Order the given files such that each file comes after the files declaring the units it
+    /// depends on. Files without dependencies between them keep their relative order.
+    pub fn compile_order(&self, files: &[String]) -> Result<Vec<String>> {
+        self.order_by(files, &self.module_file_map)
+    }
+
+    /// Order the given files, which declare units as given by `unit_files`, in compile order.
+    pub fn order_by(
+        &self,
+        files: &[String],
+        unit_files: &HashMap<String, String>,
+    ) -> Result<Vec<String>> {
+        let mut index = HashMap::new();
+        for (i, file) in files.iter().enumerate() {
+            index.entry(file).or_insert(i);
+        }
+
+        // Edges point from a file to the files depending on it.
+        let mut file_graph = Graph::<usize, (&String, &String)>::new();
+        let nodes = (0..files.len())
+            .map(|i| file_graph.add_node(i))
+            .collect::<Vec<_>>();
+        for edge in self.module_graph.raw_edges() {
+            let parent = &self.module_graph[edge.source()];
+            let dependency = &self.module_graph[edge.target()];
+            let parent_file = unit_files.get(parent).and_then(|f| index.get(f));
+            let dependency_file = unit_files.get(dependency).and_then(|f| index.get(f));
+            if let (Some(&p), Some(&d)) = (parent_file, dependency_file) {
+                if p != d {
+                    file_graph.add_edge(nodes[d], nodes[p], (parent, dependency));
+                }
+            }
+        }
+
+        // Kahn's algorithm, always picking the earliest file that is ready.
+        let mut in_degree = nodes
+            .iter()
+            .map(|&n| {
+                file_graph
+                    .neighbors_directed(n, Direction::Incoming)
+                    .count()
+            })
+            .collect::<Vec<_>>();
+        let mut ready = (0..files.len())
+            .filter(|&i| in_degree[i] == 0)
+            .collect::<BTreeSet<_>>();
+        let mut order = Vec::with_capacity(files.len());
+        while let Some(i) = ready.pop_first() {
+            order.push(files[i].clone());
+            for n in file_graph.neighbors_directed(nodes[i], Direction::Outgoing) {
+                in_degree[n.index()] -= 1;
+                if in_degree[n.index()] == 0 {
+                    ready.insert(n.index());
+                }
+            }
+        }
+
+        if order.len() < files.len() {
+            let mut cycles = Vec::new();
+            for scc in tarjan_scc(&file_graph).into_iter().filter(|c| c.len() > 1) {
+                for edge in file_graph.raw_edges() {
+                    if scc.contains(&edge.source()) && scc.contains(&edge.target()) {
+                        let (parent, dependency) = edge.weight;
+                        cycles.push(format!(
+                            "`{}` ({}) depends on `{}` ({})",
+                            parent, unit_files[parent], dependency, unit_files[dependency]
+                        ));
+                    }
+                }
+            }
+            return Err(anyhow!(
+                "Dependency cycle between files:\n\t{}",
+                cycles.join("\n\t")
+            ));
+        }
+
+        Ok(order)
+    }
+
+    pub fn prune_graph(&mut self, top_modules: &[&String]) -> Result<()> {
+        // Keep everything reachable from any of the top modules.
+        let mut test_weights = HashMap::new();
+        for top_module in top_modules {
+            let top_module = self.resolve_top(top_module)?;
+            if !self.module_graph_nodes.contains_key(&top_module) {
+                return Err(anyhow!("Module {} not found!", top_module));
+            }
+            test_weights.extend(dijkstra(
+                &self.module_graph,
+                self.module_graph_nodes[&top_module],
+                None,
+                |_| 1,
+            ));
+        }
+
+        self.module_graph
+            .retain_nodes(|_, n| test_weights.contains_key(&n));
+        self.module_graph_nodes
+            .retain(|_, v| test_weights.contains_key(v));
+
+        let test_keys = self.module_graph_nodes.clone();
+        self.pruned.extend(
+            self.module_file_map
+                .keys()
+                .filter(|k| !test_keys.contains_key(*k))
+                .cloned(),
+        );
+        self.module_file_map
+            .retain(|k, _| test_keys.contains_key(k));
+
+        self.inst_table.retain(|k| test_keys.contains_key(k));
+
+        self.rename_table.retain(|k, _| test_keys.contains_key(k));
+
+        Ok(())
+    }
+}
+
+// Returns true if this file has a library extension (.v or .sv).
+pub fn has_libext(p: &Path) -> bool {
+    matches!(
+        p.extension().and_then(OsStr::to_str),
+        Some("sv") | Some("v")
+    )
+}
+
+// Given a library filename, return the module name that this file must contain. Library files
+// must be named as module_name.v or module_name.sv.
+pub fn lib_module(p: &Path) -> Option<String> {
+    p.with_extension("").file_name()?.to_str().map(String::from)
+}
+
+// Convert the preprocessor defines into the appropriate format which is understood by `sv-parser`
+pub fn defines_to_sv_parser(defines: &HashMap<String, Option<String>>) -> Defines {
+    defines
+        .iter()
+        .map(|(name, value)| {
+            // If there is a define text add it.
+            let define_text = value
+                .as_ref()
+                .map(|x| DefineText::new(String::from(x), None));
+            (
+                name.clone(),
+                Some(Define::new(name.clone(), vec![], define_text)),
+            )
+        })
+        .collect()
+}
+
+pub fn parse_file(
+    filename: &str,
+    bundle_include_dirs: &[&Path],
+    bundle_defines: &HashMap<String, Option<Define>>,
+    strip_comments: bool,
+    unifdef: Option<&Unifdef>,
+    keep_includes: bool,
+) -> Result<ParsedFile> {
+    info!("{:?}", filename);
+
+    // Preprocess the verilog files.
+    let (pp, partial) = match unifdef {
+        Some(unifdef) => {
+            // Preprocess the partially preprocessed text, remembering where it came from.
+            let text = unifdef.process(filename)?;
+            let pp = preprocess_str(
+                &text.text,
+                filename,
+                bundle_defines,
+                bundle_include_dirs,
+                false,
+                strip_comments,
+                0,
+                0,
+            )
+            .with_context(|| format!("Failed to preprocess `{}`", filename))?;
+            let partial = PartialSource::new(text, &pp.0, Path::new(filename));
+            (pp, Some(partial))
+        }
+        None => (
+            preprocess(
+                filename,
+                bundle_defines,
+                bundle_include_dirs,
+                strip_comments,
+                false,
+            )
+            .with_context(|| format!("Failed to preprocess `{}`", filename))?,
+            None,
+        ),
+    };
+
+    let includes = match keep_includes {
+        true => included_files(&pp.0, filename)?,
+        false => vec![],
+    };
+
+    let buffer = pp.0.text().to_string();
+    let syntax_tree = parse_pp(pp)?;
+
+    Ok(ParsedFile {
+        path: String::from(filename),
+        source: buffer,
+        ast: syntax_tree.0,
+        defines: syntax_tree.1,
+        bundle: None,
+        partial,
+        includes,
+    })
+}
+
+// Parse a preprocessed file, printing the error if it fails.
+fn parse_pp(pp: (PreprocessedText, Defines)) -> Result<(SyntaxTree, Defines)> {
+    parse_sv_pp(pp.0, pp.1, false).or_else(|err| -> Result<_> {
+        let printer = Arc::new(Mutex::new(printer::Printer::new()));
+        let printer = &mut *printer.lock().unwrap();
+        print_parse_error(printer, &err, false)?;
+        Err(Error::new(err))
+    })
+}
+
+/// Find the text in a preprocessed file which stems from its `` `include `` directives.
+fn included_files(pp: &PreprocessedText, filename: &str) -> Result<Vec<Include>> {
+    let path = Path::new(filename);
+    let original =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read `{}`", filename))?;
+    let directive = Regex::new(r#"`include\s*(?:"([^"]*)"|<([^>]*)>)"#).unwrap();
+    let mut includes = vec![];
+    // The end of the last text from the file itself, in the original file.
+    let mut last = 0;
+    // The start of the current included text and the files it stems from.
+    let mut region: Option<(usize, Vec<PathBuf>)> = None;
+    for pos in 0..=pp.text().len() {
+        let next = match pp.origin(pos) {
+            Some((p, origin)) if p == path => origin,
+            // Text from other files, either included or expanded from their macros.
+            Some((p, _)) => {
+                let (_, files) = region.get_or_insert((pos, vec![]));
+                if !files.contains(p) {
+                    files.push(p.clone());
+                }
+                continue;
+            }
+            None if pos < pp.text().len() => continue,
+            None => original.len(),
+        };
+        // The text is included if the directive between the surrounding text names its file.
+        if let Some((offset, files)) = region.take() {
+            let directives = original
+                .get(last..next)
+                .map(|gap| {
+                    directive
+                        .captures_iter(gap)
+                        .filter_map(|c| {
+                            let name = c.get(1).or(c.get(2)).unwrap().as_str();
+                            files.iter().find(|f| f.ends_with(name)).cloned()
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            if !directives.is_empty() {
+                includes.push(Include {
+                    offset,
+                    len: pos - offset,
+                    directives,
+                    files,
+                });
+            }
+        }
+        last = next + 1;
+    }
+    Ok(includes)
+}
+
+/// Resolves the conditionals on a given set of macros in a file and inlines its includes, as
+/// `unifdef` does. All other conditionals as well as macro definitions and usages are kept as
+/// written, such that the result can still be configured downstream.
+pub struct Unifdef<'a> {
+    /// Macros which are defined, usages of these are replaced with their value.
+    pub defines: &'a HashMap<String, Option<String>>,
+    /// Macros which are undefined.
+    pub undefines: &'a [String],
+    /// Directories to search for included files.
+    pub include_dirs: &'a [&'a Path],
+}
+
+/// Partially preprocessed text and where it stems from.
+#[derive(Default)]
+pub struct PartialText {
+    /// The text.
+    pub text: String,
+    /// Runs `(offset, file, origin)` of the text and the files they stem from.
+    pub origins: Vec<(usize, PathBuf, usize)>,
+}
+
+impl PartialText {
+    // Append text which stems from the given offset in a file.
+    fn push(&mut self, text: &str, path: &Path, origin: usize) {
+        let contiguous = self
+            .origins
+            .last()
+            .is_some_and(|(start, p, o)| p == path && o + self.text.len() - start == origin);
+        if !contiguous && !text.is_empty() {
+            self.origins
+                .push((self.text.len(), path.to_path_buf(), origin));
+        }
+        self.text.push_str(text);
+    }
+}
+
+/// A conditional block while resolving conditionals.
+struct Conditional {
+    /// Whether the conditional is kept in the output.
+    kept: bool,
+    /// Whether the current branch is part of the output.
+    active: bool,
+    /// Whether a branch was resolved to be taken, dropping all following ones.
+    done: bool,
+}
+
+impl Unifdef<'_> {
+    /// Return the partially preprocessed text of a file.
+    pub fn process(&self, filename: &str) -> Result<PartialText> {
+        let mut out = PartialText::default();
+        self.process_file(Path::new(filename), &mut out, 0)?;
+        Ok(out)
+    }
+
+    // Whether a macro is known to be defined or undefined.
+    fn is_defined(&self, name: &str) -> Option<bool> {
+        if self.defines.contains_key(name) {
+            Some(true)
+        } else if self.undefines.iter().any(|u| u == name) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    // Find an included file in the working directory or the include directories.
+    fn find_include(&self, name: &str) -> Option<PathBuf> {
+        let path = PathBuf::from(name);
+        if path.exists() || !path.is_relative() {
+            return Some(path);
+        }
+        self.include_dirs
+            .iter()
+            .map(|dir| dir.join(name))
+            .find(|path| path.exists())
+    }
+
+    fn process_file(&self, path: &Path, out: &mut PartialText, depth: usize) -> Result<()> {
+        // Same limit as `sv-parser`.
+        if depth > 128 {
+            return Err(anyhow!("Too many nested includes in `{}`", path.display()));
+        }
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read `{}`", path.display()))?;
+        self.process_text(&text, 0, text.len(), path, out, depth)
+    }
+
+    // Process the text between `start` and `stop` of a file.
+    fn process_text(
+        &self,
+        text: &str,
+        start: usize,
+        stop: usize,
+        path: &Path,
+        out: &mut PartialText,
+        depth: usize,
+    ) -> Result<()> {
+        let directive = Regex::new(r"^`([a-zA-Z_][a-zA-Z0-9_$]*)").unwrap();
+        let condition = Regex::new(r"^\s*([a-zA-Z_][a-zA-Z0-9_$]*)").unwrap();
+        let include = Regex::new(r#"^\s*(?:"([^"]*)"|<([^>]*)>)"#).unwrap();
+        let unmatched = |name: &str| anyhow!("Unmatched `{} in `{}`", name, path.display());
+
+        let mut stack: Vec<Conditional> = vec![];
+        let mut i = start;
+        while i < stop {
+            let emit = stack.iter().all(|c| c.active);
+            let rest = &text[i..stop];
+            let caps = match directive.captures(rest) {
+                Some(caps) => caps,
+                None => {
+                    // Copy everything up to the next comment, string or directive.
+                    let end = if rest.starts_with("//") {
+                        rest.find('\n').unwrap_or(rest.len())
+                    } else if let Some(comment) = rest.strip_prefix("/*") {
+                        comment.find("*/").map_or(rest.len(), |e| e + 4)
+                    } else if rest.starts_with('"') {
+                        let mut escaped = false;
+                        rest.char_indices()
+                            .skip(1)
+                            .find(|&(_, c)| {
+                                let end = !escaped && (c == '"' || c == '\n');
+                                escaped = !escaped && c == '\\';
+                                end
+                            })
+                            .map_or(rest.len(), |(e, _)| e + 1)
+                    } else {
+                        rest.char_indices()
+                            .skip(1)
+                            .find(|&(_, c)| matches!(c, '/' | '"' | '`'))
+                            .map_or(rest.len(), |(e, _)| e)
+                    };
+                    if emit {
+                        out.push(&rest[..end], path, i);
+                    }
+                    i += end;
+                    continue;
+                }
+            };
+            let name = caps.get(1).unwrap().as_str();
+            let mut end = caps.get(0).unwrap().end();
+            // Whether the directive itself is part of the output.
+            let mut visible = emit;
+            // The replacement of the directive, `None` to drop it.
+            let repl = match name {
+                "ifdef" | "ifndef" | "elsif" | "else" | "endif" => {
+                    let cond = condition
+                        .captures(&rest[end..])
+                        .filter(|_| name != "else" && name != "endif")
+                        .map(|c| c.get(1).unwrap());
+                    if let Some(cond) = cond {
+                        end += cond.end();
+                    }
+                    let known = cond
+                        .and_then(|c| self.is_defined(c.as_str()))
+                        .map(|d| d != (name == "ifndef"));
+                    if name == "ifdef" || name == "ifndef" {
+                        stack.push(Conditional {
+                            kept: known.is_none(),
+                            active: known.unwrap_or(true),
+                            done: known.unwrap_or(false),
+                        });
+                        known.is_none().then(|| rest[..end].to_string())
+                    } else {
+                        visible = stack.iter().rev().skip(1).all(|c| c.active);
+                        let c = stack.last_mut().ok_or_else(|| unmatched(name))?;
+                        match name {
+                            "endif" => {
+                                let kept = c.kept;
+                                stack.pop();
+                                kept.then(|| rest[..end].to_string())
+                            }
+                            _ if c.done => {
+                                c.active = false;
+                                None
+                            }
+                            "else" => {
+                                c.active = true;
+                                c.done = true;
+                                c.kept.then(|| rest[..end].to_string())
+                            }
+                            _ => match known {
+                                // The branch is taken, drop the following ones.
+                                Some(true) => {
+                                    c.active = true;
+                                    c.done = true;
+                                    c.kept.then(|| "`else".to_string())
+                                }
+                                Some(false) => {
+                                    c.active = false;
+                                    None
+                                }
+                                // The branch opens the conditional if all previous ones were dropped.
+                                None => {
+                                    c.active = true;
+                                    let repl = match c.kept {
+                                        true => rest[..end].to_string(),
+                                        false => format!("`ifdef{}", &rest["`elsif".len()..end]),
+                                    };
+                                    c.kept = true;
+                                    Some(repl)
+                                }
+                            },
+                        }
+                    }
+                }
+                "include" if emit => {
+                    let caps = include
+                        .captures(&rest[end..])
+                        .ok_or_else(|| anyhow!("Malformed `include in `{}`", path.display()))?;
+                    end += caps.get(0).unwrap().end();
+                    let file = caps.get(1).or(caps.get(2)).unwrap().as_str();
+                    let file = self.find_include(file).ok_or_else(|| {
+                        anyhow!("Cannot find `{}` included in `{}`", file, path.display())
+                    })?;
+                    self.process_file(&file, out, depth + 1)?;
+                    Some(String::new())
+                }
+                // Definitions are kept, including their continued lines, with the given macros
+                // resolved within their text as well.
+                "define" => {
+                    let mut escaped = false;
+                    end = rest
+                        .char_indices()
+                        .find(|&(_, c)| {
+                            let end = !escaped && c == '\n';
+                            escaped = c == '\\' || (escaped && c == '\r');
+                            end
+                        })
+                        .map_or(rest.len(), |(e, _)| e);
+                    let mut body = PartialText::default();
+                    self.process_text(text, i + name.len() + 1, i + end, path, &mut body, depth)?;
+                    // The last line of the text may have been dropped, ending it with a line
+                    // continuation.
+                    let mut body = body.text.trim_end();
+                    if let Some(b) = body.strip_suffix('\\') {
+                        body = b.trim_end();
+                    }
+                    Some(format!("`define{}", body))
+                }
+                _ => match self.defines.get(name) {
+                    Some(value) => Some(value.clone().unwrap_or_default()),
+                    None => Some(rest[..end].to_string()),
+                },
+            };
+            if visible {
+                let line_start = text[..i].rfind('\n').map_or(0, |p| p + 1);
+                match repl {
+                    Some(repl) => {
+                        // Restore the indentation if the preceding text was dropped.
+                        let indent = &text[line_start..i];
+                        if indent.trim().is_empty()
+                            && (out.text.is_empty() || out.text.ends_with('\n'))
+                        {
+                            out.push(indent, path, line_start);
+                        }
+                        out.push(&repl, path, i);
+                    }
+                    // Drop lines which only contain the directive, continued within a
+                    // definition.
+                    None => {
+                        let line_end = rest[end..].find('\n').map(|p| end + p + 1);
+                        if let Some(line_end) = line_end.filter(|&e| {
+                            text[line_start..i].trim().is_empty()
+                                && rest[end..e].trim().trim_end_matches('\\').trim().is_empty()
+                        }) {
+                            out.text
+                                .truncate(out.text.trim_end_matches([' ', '\t']).len());
+                            end = line_end;
+                        }
+                    }
+                }
+            }
+            i += end;
+        }
+        if !stack.is_empty() {
+            return Err(anyhow!("Unterminated conditional in `{}`", path.display()));
+        }
+        Ok(())
+    }
+}
+
+/// The state of the compilation-unit directives at the end of a file, relative to the defaults.
+#[derive(Debug, Default)]
+pub struct DirectiveState {
+    /// A `default_nettype` other than `wire`.
+    pub default_nettype: Option<String>,
+    /// Within `celldefine.
+    pub celldefine: bool,
+    /// Within `unconnected_drive.
+    pub unconnected_drive: bool,
+    /// A `timescale was set.
+    pub timescale: bool,
+    /// Number of open `begin_keywords.
+    pub keywords: usize,
+}
+
+impl DirectiveState {
+    /// Track the directives of a file in order of appearance.
+    pub fn new(st: &SyntaxTree) -> Self {
+        let mut state = Self::default();
+        for node in st {
+            match node {
+                RefNode::DefaultNettypeCompilerDirective(x) => {
+                    let value = st.get_str(&x.nodes.2).unwrap().trim();
+                    state.default_nettype = Some(value.to_string()).filter(|v| v != "wire");
+                }
+                RefNode::CelldefineDriveCompilerDirective(_) => state.celldefine = true,
+                RefNode::EndcelldefineDriveCompilerDirective(_) => state.celldefine = false,
+                RefNode::UnconnectedDriveCompilerDirective(_) => state.unconnected_drive = true,
+                RefNode::NounconnectedDriveCompilerDirective(_) => state.unconnected_drive = false,
+                RefNode::TimescaleCompilerDirective(_) => state.timescale = true,
+                RefNode::KeywordsDirective(_) => state.keywords += 1,
+                RefNode::EndkeywordsDirective(_) => {
+                    state.keywords = state.keywords.saturating_sub(1)
+                }
+                // `resetall does not affect `begin_keywords.
+                RefNode::ResetallCompilerDirective(_) => {
+                    state = Self {
+                        keywords: state.keywords,
+                        ..Self::default()
+                    }
+                }
+                _ => (),
+            }
+        }
+        state
+    }
+
+    /// The directives which restore the default state.
+    pub fn resets(&self) -> Vec<&'static str> {
+        let mut resets = vec!["`end_keywords"; self.keywords];
+        // There is no other way to unset the `timescale.
+        if self.timescale {
+            resets.push("`resetall");
+        } else {
+            if self.default_nettype.is_some() {
+                resets.push("`default_nettype wire");
+            }
+            if self.celldefine {
+                resets.push("`endcelldefine");
+            }
+            if self.unconnected_drive {
+                resets.push("`nounconnected_drive");
+            }
+        }
+        resets
+    }
+}
+
+/// Find the declarations of design units and their identifiers: modules, interfaces, packages,
+/// programs, UDPs, configs, as well as classes and checkers in the compilation-unit scope.
+pub fn unit_declarations(st: &SyntaxTree) -> Vec<(RefNode<'_>, RefNode<'_>)> {
+    let mut units = vec![];
+    for node in st {
+        let id = match &node {
+            RefNode::ModuleDeclarationAnsi(x) => unwrap_node!(*x, ModuleIdentifier),
+            RefNode::ModuleDeclarationNonansi(x) => unwrap_node!(*x, ModuleIdentifier),
+            RefNode::InterfaceDeclaration(x) => unwrap_node!(*x, InterfaceIdentifier),
+            RefNode::PackageDeclaration(x) => unwrap_node!(*x, PackageIdentifier),
+            RefNode::ProgramDeclaration(x) => unwrap_node!(*x, ProgramIdentifier),
+            RefNode::UdpDeclaration(x) => unwrap_node!(*x, UdpIdentifier),
+            RefNode::ConfigDeclaration(x) => unwrap_node!(*x, ConfigIdentifier),
+            // Classes and checkers declared within other units are local to them.
+            RefNode::DescriptionPackageItem(x) => {
+                if let Some(decl) = unwrap_node!(
+                    *x,
+                    ClassDeclaration,
+                    InterfaceClassDeclaration,
+                    CheckerDeclaration
+                ) {
+                    let id = unwrap_node!(decl.clone(), ClassIdentifier, CheckerIdentifier);
+                    units.push((decl, id.unwrap()));
+                }
+                continue;
+            }
+            RefNode::Description(sv_parser::Description::InterfaceClassDeclaration(x)) => {
+                let decl = RefNode::from(&**x);
+                let id = unwrap_node!(decl.clone(), ClassIdentifier);
+                units.push((decl, id.unwrap()));
+                continue;
+            }
+            _ => continue,
+        };
+        units.push((node, id.unwrap()));
+    }
+    units
+}
+
+/// Find the typedefs, functions, tasks and parameters declared in the compilation-unit scope, with
+/// their kind and identifier.
+pub fn unit_items(st: &SyntaxTree) -> Vec<(&'static str, RefNode<'_>)> {
+    use sv_parser::{
+        DataDeclaration, FunctionBodyDeclaration, PackageItem, PackageOrGenerateItemDeclaration,
+        TaskBodyDeclaration, TypeDeclaration,
+    };
+    let mut items = vec![];
+    for node in st {
+        let item = match node {
+            RefNode::DescriptionPackageItem(x) => match &x.nodes.1 {
+                PackageItem::PackageOrGenerateItemDeclaration(x) => x,
+                _ => continue,
+            },
+            _ => continue,
+        };
+        match &**item {
+            PackageOrGenerateItemDeclaration::DataDeclaration(x) => {
+                if let DataDeclaration::TypeDeclaration(x) = &**x {
+                    let id = match &**x {
+                        TypeDeclaration::DataType(x) => &x.nodes.2,
+                        TypeDeclaration::Interface(x) => &x.nodes.5,
+                        TypeDeclaration::Reserved(x) => &x.nodes.2,
+                    };
+                    items.push(("typedef", RefNode::from(id)));
+                }
+            }
+            // Out-of-block declarations of class methods are not compilation-unit items.
+            PackageOrGenerateItemDeclaration::FunctionDeclaration(x) => match &x.nodes.2 {
+                FunctionBodyDeclaration::WithoutPort(x) if x.nodes.1.is_none() => {
+                    items.push(("function", RefNode::from(&x.nodes.2)))
+                }
+                FunctionBodyDeclaration::WithPort(x) if x.nodes.1.is_none() => {
+                    items.push(("function", RefNode::from(&x.nodes.2)))
+                }
+                _ => (),
+            },
+            PackageOrGenerateItemDeclaration::TaskDeclaration(x) => match &x.nodes.2 {
+                TaskBodyDeclaration::WithoutPort(x) if x.nodes.0.is_none() => {
+                    items.push(("task", RefNode::from(&x.nodes.1)))
+                }
+                TaskBodyDeclaration::WithPort(x) if x.nodes.0.is_none() => {
+                    items.push(("task", RefNode::from(&x.nodes.1)))
+                }
+                _ => (),
+            },
+            PackageOrGenerateItemDeclaration::LocalParameterDeclaration(_)
+            | PackageOrGenerateItemDeclaration::ParameterDeclaration(_) => {
+                for node in RefNode::from(&**item) {
+                    match node {
+                        RefNode::ParamAssignment(x) => {
+                            items.push(("parameter", RefNode::from(&x.nodes.0)))
+                        }
+                        RefNode::TypeAssignment(x) => {
+                            items.push(("parameter", RefNode::from(&x.nodes.0)))
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    items
+}
+
+/// Find the offsets of the names in named parameter and port assignments, which refer to the
+/// parameters and ports of the instantiated unit rather than to anything in scope.
+fn named_assignments(st: &SyntaxTree) -> HashSet<usize> {
+    let mut offsets = HashSet::new();
+    for node in st {
+        let id = match node {
+            RefNode::NamedParameterAssignment(x) => RefNode::from(&x.nodes.1),
+            RefNode::NamedPortConnectionIdentifier(x) => RefNode::from(&x.nodes.2),
+            _ => continue,
+        };
+        offsets.insert(get_identifier(st, id).1.offset);
+    }
+    offsets
+}
+
+/// Check whether a node opens a scope in which names can be declared.
+fn is_scope(node: &RefNode) -> bool {
+    matches!(
+        node,
+        RefNode::ModuleDeclaration(_)
+            | RefNode::InterfaceDeclaration(_)
+            | RefNode::ProgramDeclaration(_)
+            | RefNode::PackageDeclaration(_)
+            | RefNode::CheckerDeclaration(_)
+            | RefNode::ClassDeclaration(_)
+            | RefNode::InterfaceClassDeclaration(_)
+            | RefNode::FunctionDeclaration(_)
+            | RefNode::TaskDeclaration(_)
+            | RefNode::SeqBlock(_)
+            | RefNode::ParBlock(_)
+            | RefNode::GenerateBlock(_)
+    )
+}
+
+/// Find the names declared directly within a scope. The names of nested functions, tasks and
+/// classes count as declared in the scope, but not the names declared within them.
+fn scope_declarations(st: &SyntaxTree, scope: RefNode, named: &HashSet<usize>) -> Vec<String> {
+    use sv_parser::TypeDeclaration;
+    let mut names = vec![];
+    // The scope itself is at depth 1.
+    let mut depth = 0;
+    for event in scope.into_iter().event() {
+        let node = match event {
+            NodeEvent::Enter(node) => node,
+            NodeEvent::Leave(node) => {
+                if is_scope(&node) {
+                    depth -= 1;
+                }
+                continue;
+            }
+        };
+        if is_scope(&node) {
+            depth += 1;
+            if depth != 2 {
+                continue;
+            }
+            let id = match node {
+                RefNode::FunctionDeclaration(_) => unwrap_node!(node, FunctionIdentifier),
+                RefNode::TaskDeclaration(_) => unwrap_node!(node, TaskIdentifier),
+                RefNode::ClassDeclaration(_) | RefNode::InterfaceClassDeclaration(_) => {
+                    unwrap_node!(node, ClassIdentifier)
+                }
+                _ => None,
+            };
+            if let Some(id) = id {
+                names.push(get_identifier(st, id).0);
+            }
+            continue;
+        }
+        if depth > 1 {
+            continue;
+        }
+        let id = match node {
+            RefNode::ParamAssignment(x) => RefNode::from(&x.nodes.0),
+            RefNode::TypeAssignment(x) => RefNode::from(&x.nodes.0),
+            RefNode::TypeDeclaration(TypeDeclaration::DataType(x)) => RefNode::from(&x.nodes.2),
+            RefNode::TypeDeclaration(TypeDeclaration::Interface(x)) => RefNode::from(&x.nodes.5),
+            RefNode::TypeDeclaration(TypeDeclaration::Reserved(x)) => RefNode::from(&x.nodes.2),
+            RefNode::VariableIdentifier(_)
+            | RefNode::NetIdentifier(_)
+            | RefNode::GenvarIdentifier(_)
+            | RefNode::PortIdentifier(_) => node,
+            _ => continue,
+        };
+        let (name, loc) = get_identifier(st, id);
+        if !named.contains(&loc.offset) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// Find the bind directives in the compilation-unit scope, with the names of the units they bind
+/// into and the identifier of the bound unit. For a bind into an instance, the unit is the root of
+/// the instance path.
+pub fn bind_directives(st: &SyntaxTree) -> Vec<(RefNode<'_>, Vec<RefNode<'_>>, RefNode<'_>)> {
+    let mut binds = vec![];
+    for node in st {
+        if let RefNode::DescriptionBindDirective(x) = node {
+            let (targets, bound) = bind_targets(&x.nodes.1);
+            binds.push((node.clone(), targets, bound));
+        }
+    }
+    binds
+}
+
+/// The names of the units a bind directive binds into and the identifier of the bound unit.
+pub fn bind_targets(x: &sv_parser::BindDirective) -> (Vec<RefNode<'_>>, RefNode<'_>) {
+    use sv_parser::{BindDirective, BindTargetScope};
+    let (targets, instantiation) = match x {
+        BindDirective::Scope(y) => {
+            let target = match &y.nodes.1 {
+                BindTargetScope::ModuleIdentifier(x) => RefNode::from(&**x),
+                BindTargetScope::InterfaceIdentifier(x) => RefNode::from(&**x),
+            };
+            (vec![target], &y.nodes.3)
+        }
+        BindDirective::Instance(y) => (vec![hierarchical_root(&y.nodes.1.nodes.0)], &y.nodes.2),
+    };
+    // The bound unit may be parsed as any kind of instantiation.
+    let bound = unwrap_node!(
+        RefNode::from(instantiation),
+        ModuleIdentifier,
+        ProgramIdentifier,
+        InterfaceIdentifier,
+        CheckerIdentifier
+    );
+    (targets, bound.unwrap())
+}
+
+/// Get the first component of a hierarchical identifier, i.e., `top` in `$root.top.i_dut.sig`.
+pub fn hierarchical_root(x: &sv_parser::HierarchicalIdentifier) -> RefNode<'_> {
+    match x.nodes.1.first() {
+        Some((id, _, _)) => RefNode::from(id),
+        None => RefNode::from(&x.nodes.2),
+    }
+}
+
+/// Collect the names of instances, variables, nets, ports and generate blocks declared in a file,
+/// which may shadow the names of units at the root of hierarchical references.
+pub fn local_names(st: &SyntaxTree) -> HashSet<String> {
+    st.into_iter()
+        .filter(|node| {
+            matches!(
+                node,
+                RefNode::InstanceIdentifier(_)
+                    | RefNode::VariableIdentifier(_)
+                    | RefNode::NetIdentifier(_)
+                    | RefNode::PortIdentifier(_)
+                    | RefNode::GenerateBlockIdentifier(_)
+                    | RefNode::InterfaceInstanceIdentifier(_)
+            )
+        })
+        .map(|node| get_identifier(st, node).0)
+        .collect()
+}
+
+/// The ports of a module declaration with their directions and data types, including unpacked
+/// dimensions. Returns `None` for other declarations.
+fn module_ports(st: &SyntaxTree, decl: RefNode) -> Option<Vec<(String, String, String)>> {
+    let items = blackbox_items(decl)?;
+    let header = items[0].0.clone();
+    // Ports are declared in the header of ANSI modules, and in the body of others.
+    let nodes = match &header {
+        RefNode::ModuleAnsiHeader(_) => vec![header.clone()],
+        _ => items
+            .into_iter()
+            .skip(1)
+            .filter(|(_, keep)| *keep)
+            .map(|(item, _)| item)
+            .collect(),
+    };
+    let mut ports: Vec<(String, String, String)> = vec![];
+    // Ports without a direction or data type inherit them from the preceding one.
+    let mut direction = String::from("inout");
+    let mut data_type = String::new();
+    let text = |node: RefNode| {
+        node_locate(node)
+            .and_then(|loc| st.get_str(&loc))
+            .map_or(String::new(), normalize_type)
+    };
+    for node in nodes {
+        for node in node {
+            match node {
+                RefNode::PortDirection(x) => {
+                    direction = st.get_str(x).unwrap().trim().to_string();
+                    data_type.clear();
+                }
+                RefNode::InputDeclaration(_) => direction = String::from("input"),
+                RefNode::OutputDeclaration(_) => direction = String::from("output"),
+                RefNode::InoutDeclaration(_) => direction = String::from("inout"),
+                RefNode::RefDeclaration(_) => direction = String::from("ref"),
+                RefNode::InterfacePortHeader(_) => {
+                    direction = String::from("interface");
+                    data_type = text(node);
+                }
+                RefNode::NetPortType(_) | RefNode::VariablePortType(_) => data_type = text(node),
+                RefNode::PortIdentifier(_) => {
+                    let name = get_identifier(st, node).0;
+                    ports.push((name, direction.clone(), data_type.clone()));
+                }
+                // Unpacked dimensions follow the name of the port.
+                RefNode::UnpackedDimension(_) => {
+                    if let Some((_, _, ty)) = ports.last_mut() {
+                        ty.push_str(&text(node));
                     }
                 }
-                // add the parsed file to the vector.
-                files.push(pf);
+                _ => (),
             }
-            Err(e) => info!("error loading library: {}", e),
         }
     }
+    if let RefNode::ModuleAnsiHeader(_) = header {
+        return Some(ports);
+    }
 
-    pub fn prune_graph(&mut self, top_module: &str) -> Result<()> {
-        if !self.module_graph_nodes.contains_key(top_module) {
-            return Err(anyhow!("Module {} not found!", top_module));
-        }
-        let test_weights = dijkstra(
-            &self.module_graph,
-            self.module_graph_nodes[top_module],
-            None,
-            |_| 1,
-        );
-
-        self.module_graph
-            .retain_nodes(|_, n| test_weights.contains_key(&n));
-        self.module_graph_nodes
-            .retain(|_, v| test_weights.contains_key(v));
-
-        let test_keys = self.module_graph_nodes.clone();
-        self.module_file_map
-            .retain(|k, _| test_keys.contains_key(k));
+    // The ports of other modules are named in the header, possibly differently than the
+    // declared nets they are bound to.
+    let mut named = vec![];
+    for node in header {
+        let (name, net) = match node {
+            RefNode::Port(sv_parser::Port::Named(x)) => (
+                RefNode::from(&x.nodes.1),
+                (x.nodes.2.nodes.1.as_ref()).and_then(|expr| unwrap_node!(expr, PortIdentifier)),
+            ),
+            RefNode::Port(sv_parser::Port::NonNamed(x)) => match &x.nodes.0 {
+                Some(sv_parser::PortExpression::PortReference(x)) => {
+                    (RefNode::from(&x.nodes.0), Some(RefNode::from(&x.nodes.0)))
+                }
+                _ => continue,
+            },
+            _ => continue,
+        };
+        let net = net.map(|net| get_identifier(st, net).0);
+        let (direction, data_type) = ports
+            .iter()
+            .find(|(port, _, _)| Some(port) == net.as_ref())
+            .map_or((String::from("inout"), String::new()), |(_, d, t)| {
+                (d.clone(), t.clone())
+            });
+        named.push((get_identifier(st, name).0, direction, data_type));
+    }
+    Some(named)
+}
 
-        self.inst_table.retain(|k| test_keys.contains_key(k));
+/// Normalize the text of a data type for comparison, dropping comments and insignificant
+/// whitespace.
+fn normalize_type(text: &str) -> String {
+    let comment = Regex::new(r"(?s)//[^\n]*|/\*.*?\*/").unwrap();
+    let punct = Regex::new(r"\s*([^\w\s$])\s*").unwrap();
+    let text = comment.replace_all(text, " ");
+    let text = punct.replace_all(&text, "$1");
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
 
-        self.rename_table.retain(|k, _| test_keys.contains_key(k));
+/// The names of the parameters of a module declaration which can be overridden.
+fn parameter_names(st: &SyntaxTree, decl: RefNode) -> Vec<String> {
+    module_parameters(st, decl)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect()
+}
 
-        Ok(())
+/// The parameters of a module declaration which can be overridden, with their assignments.
+/// Returns `None` for other declarations.
+fn module_parameters<'a>(st: &SyntaxTree, decl: RefNode<'a>) -> Option<Vec<(String, RefNode<'a>)>> {
+    let items = blackbox_items(decl)?;
+    let header = items[0].0.clone();
+    let mut params = parameter_assignments(st, header.clone());
+    // Parameters declared in the body are local if the header has a parameter port list.
+    if unwrap_node!(header, ParameterPortList).is_none() {
+        for (item, _) in items.into_iter().skip(1).filter(|(_, keep)| *keep) {
+            if let Some(x @ RefNode::ParameterDeclaration(_)) =
+                unwrap_node!(item, ParameterDeclaration)
+            {
+                params.extend(parameter_assignments(st, x));
+            }
+        }
     }
+    Some(params)
 }
 
-// Returns true if this file has a library extension (.v or .sv).
-pub fn has_libext(p: &Path) -> bool {
-    matches!(
-        p.extension().and_then(OsStr::to_str),
-        Some("sv") | Some("v")
-    )
+/// The local parameters of a module declaration, including the parameters declared in the body
+/// of a module with a parameter port list.
+fn module_localparams(st: &SyntaxTree, decl: RefNode) -> Vec<String> {
+    let overridable = parameter_names(st, decl.clone());
+    let mut locals = vec![];
+    for (item, _) in blackbox_items(decl)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(_, keep)| *keep)
+    {
+        for node in item {
+            if let RefNode::ParamAssignment(_) | RefNode::TypeAssignment(_) = node {
+                let (name, _) = get_identifier(st, node);
+                if !overridable.contains(&name) {
+                    locals.push(name);
+                }
+            }
+        }
+    }
+    locals
 }
 
-// Given a library filename, return the module name that this file must contain. Library files
-// must be named as module_name.v or module_name.sv.
-pub fn lib_module(p: &Path) -> Option<String> {
-    p.with_extension("").file_name()?.to_str().map(String::from)
+/// The parameters and type parameters declared in a node with their assignments, except local
+/// ones.
+fn parameter_assignments<'a>(st: &SyntaxTree, node: RefNode<'a>) -> Vec<(String, RefNode<'a>)> {
+    let mut params = vec![];
+    let mut local = 0;
+    for event in node.into_iter().event() {
+        match event {
+            NodeEvent::Enter(RefNode::LocalParameterDeclaration(_)) => local += 1,
+            NodeEvent::Leave(RefNode::LocalParameterDeclaration(_)) => local -= 1,
+            NodeEvent::Enter(x @ RefNode::ParamAssignment(_))
+            | NodeEvent::Enter(x @ RefNode::TypeAssignment(_))
+                if local == 0 =>
+            {
+                params.push((get_identifier(st, x.clone()).0, x));
+            }
+            _ => (),
+        }
+    }
+    params
 }
 
-// Convert the preprocessor defines into the appropriate format which is understood by `sv-parser`
-pub fn defines_to_sv_parser(defines: &HashMap<String, Option<String>>) -> Defines {
-    return defines
+/// Pick the entry of the bundle closest to `bundle`: the same bundle, then the closest preceding
+/// one, and finally the closest following one. Library files (`None`) come after all bundles.
+fn closest_bundle<T>(entries: &[(Option<usize>, T)], bundle: Option<usize>) -> Option<&T> {
+    let position = bundle.unwrap_or(usize::MAX);
+    entries
         .iter()
-        .map(|(name, value)| {
-            // If there is a define text add it.
-            let define_text = value
-                .as_ref()
-                .map(|x| DefineText::new(String::from(x), None));
-            (
-                name.clone(),
-                Some(Define::new(name.clone(), vec![], define_text)),
-            )
+        .min_by_key(|(b, _)| {
+            let b = b.unwrap_or(usize::MAX);
+            match b.cmp(&position) {
+                std::cmp::Ordering::Equal => (0, 0),
+                std::cmp::Ordering::Less => (1, position - b),
+                std::cmp::Ordering::Greater => (2, b - position),
+            }
         })
-        .collect();
+        .map(|(_, entry)| entry)
 }
 
-pub fn parse_file(
-    filename: &str,
-    bundle_include_dirs: &[&Path],
-    bundle_defines: &HashMap<String, Option<Define>>,
-    strip_comments: bool,
-) -> Result<ParsedFile> {
-    info!("{:?}", filename);
-
-    // Preprocess the verilog files.
-    let pp = preprocess(
-        filename,
-        bundle_defines,
-        bundle_include_dirs,
-        strip_comments,
-        false,
-    )
-    .with_context(|| format!("Failed to preprocess `{}`", filename))?;
-
-    let buffer = pp.0.text().to_string();
-    let syntax_tree = parse_sv_pp(pp.0, pp.1, false).or_else(|err| -> Result<_> {
-        let printer = Arc::new(Mutex::new(printer::Printer::new()));
-        let printer = &mut *printer.lock().unwrap();
-        print_parse_error(printer, &err, false)?;
-        Err(Error::new(err))
-    })?;
+/// Split a module declaration into its header and body items, and tell which of them a black
+/// box keeps. Returns `None` for other declarations.
+fn blackbox_items(decl: RefNode) -> Option<Vec<(RefNode, bool)>> {
+    let (header, timeunits, body) = match decl {
+        RefNode::ModuleDeclarationAnsi(x) => (
+            RefNode::from(&x.nodes.0),
+            &x.nodes.1,
+            x.nodes.2.iter().map(RefNode::from).collect::<Vec<_>>(),
+        ),
+        RefNode::ModuleDeclarationNonansi(x) => (
+            RefNode::from(&x.nodes.0),
+            &x.nodes.1,
+            x.nodes.2.iter().map(RefNode::from).collect::<Vec<_>>(),
+        ),
+        _ => return None,
+    };
+    let mut items = vec![(header, true)];
+    if let Some(x) = timeunits {
+        items.push((RefNode::from(x), true));
+    }
+    for item in body {
+        let offset = node_locate(item.clone()).map(|loc| loc.offset);
+        // Only items which are such a declaration themselves are kept, not ones containing it.
+        let keep = unwrap_node!(
+            item.clone(),
+            ParameterDeclaration,
+            LocalParameterDeclaration,
+            PortDeclaration,
+            TypeDeclaration,
+            PackageImportDeclaration
+        )
+        .is_some_and(|x| node_locate(x).map(|loc| loc.offset) == offset);
+        items.push((item, keep));
+    }
+    Some(items)
+}
 
-    Ok(ParsedFile {
-        path: String::from(filename),
-        source: buffer,
-        ast: syntax_tree.0,
-        defines: syntax_tree.1,
+/// Get the location of the complete source text of a node.
+pub fn node_locate(node: RefNode) -> Option<Locate> {
+    let mut locs = node.into_iter().filter_map(|n| match n {
+        RefNode::Locate(x) => Some(*x),
+        _ => None,
+    });
+    let first = locs.next()?;
+    let last = locs.last().unwrap_or(first);
+    Some(Locate {
+        offset: first.offset,
+        line: first.line,
+        len: last.offset + last.len - first.offset,
     })
 }
 
@@ -947,6 +4005,280 @@ pub fn get_calling_module(st: &SyntaxTree, node: RefNode) -> Option<(String, Loc
     None
 }
 
+/// How to handle multiple declarations with the same name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Abort pickling.
+    Error,
+    /// Report the duplicates, but keep all of them.
+    #[default]
+    Warn,
+    /// Keep the first declaration, strip all later ones.
+    KeepFirst,
+    /// Keep the last declaration, strip all earlier ones.
+    KeepLast,
+}
+
+/// How to handle `timeunit`, `timeprecision` and `` `timescale `` in the sources.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum TimeunitMode {
+    /// Keep all of them.
+    Keep,
+    /// Remove `timeunit` and `timeprecision` declarations.
+    #[default]
+    Remove,
+    /// Remove all of them and emit a single `` `timescale `` instead, either the given one or one
+    /// derived from the sources.
+    Normalize(Option<Timescale>),
+}
+
+/// A new default value for a parameter of a module, given as `MODULE.NAME=VALUE`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParamOverride {
+    pub module: String,
+    pub name: String,
+    pub value: String,
+}
+
+impl std::str::FromStr for ParamOverride {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let expected = || anyhow!("Expected `MODULE.NAME=VALUE` as parameter, found `{}`", s);
+        let (path, value) = s.split_once('=').ok_or_else(expected)?;
+        let (module, name) = path.split_once('.').ok_or_else(expected)?;
+        let (module, name, value) = (module.trim(), name.trim(), value.trim());
+        if module.is_empty() || name.is_empty() || value.is_empty() {
+            return Err(expected());
+        }
+        Ok(ParamOverride {
+            module: module.to_string(),
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for ParamOverride {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}={}", self.module, self.name, self.value)
+    }
+}
+
+/// A module whose declaration is replaced by the one in another file, given as `MODULE=FILE`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Substitution {
+    pub module: String,
+    pub file: String,
+}
+
+impl std::str::FromStr for Substitution {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some((module, file)) if !module.is_empty() && !file.is_empty() => Ok(Substitution {
+                module: module.to_string(),
+                file: file.to_string(),
+            }),
+            _ => Err(anyhow!(
+                "Expected `MODULE=FILE` as substitution, found `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// A time unit and precision, in femtoseconds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Timescale {
+    pub unit: u64,
+    pub precision: u64,
+}
+
+impl std::str::FromStr for Timescale {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (unit, precision) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("Expected `UNIT/PRECISION` as timescale, found `{}`", s))?;
+        let parse = |t: &str| {
+            parse_time(t).ok_or_else(|| anyhow!("Invalid time `{}` in timescale `{}`", t, s))
+        };
+        let timescale = Timescale {
+            unit: parse(unit)?,
+            precision: parse(precision)?,
+        };
+        if timescale.precision > timescale.unit {
+            return Err(anyhow!(
+                "Time precision is coarser than the time unit in `{}`",
+                s
+            ));
+        }
+        Ok(timescale)
+    }
+}
+
+impl std::fmt::Display for Timescale {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            format_time(self.unit),
+            format_time(self.precision)
+        )
+    }
+}
+
+const TIME_UNITS: [(&str, u64); 6] = [
+    ("s", 1_000_000_000_000_000),
+    ("ms", 1_000_000_000_000),
+    ("us", 1_000_000_000),
+    ("ns", 1_000_000),
+    ("ps", 1_000),
+    ("fs", 1),
+];
+
+/// Parse a time literal such as `10ns` into femtoseconds.
+pub fn parse_time(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let value = s[..split].parse::<u64>().ok()?;
+    let unit = s[split..].trim();
+    let (_, scale) = TIME_UNITS.iter().find(|(u, _)| *u == unit)?;
+    value.checked_mul(*scale)
+}
+
+/// Format femtoseconds as a time literal with the coarsest exact unit.
+pub fn format_time(fs: u64) -> String {
+    let (unit, scale) = TIME_UNITS
+        .iter()
+        .find(|(_, scale)| fs.is_multiple_of(*scale))
+        .unwrap();
+    format!("{}{}", fs / scale, unit)
+}
+
+/// Collect the time units and precisions declared in a file, in order of appearance.
+pub fn timescales(st: &SyntaxTree) -> Vec<(Option<u64>, Option<u64>)> {
+    use sv_parser::TimeunitsDeclaration;
+    let time = |literal: &sv_parser::TimeLiteral| st.get_str(literal).and_then(parse_time);
+    let mut found = vec![];
+    for node in st {
+        match node {
+            RefNode::TimescaleCompilerDirective(x) => {
+                let time = |value: &sv_parser::UnsignedNumber, unit: &sv_parser::TimeUnit| {
+                    let value = st.get_str(value)?.trim();
+                    parse_time(&format!("{}{}", value, st.get_str(unit)?))
+                };
+                found.push((time(&x.nodes.2, &x.nodes.3), time(&x.nodes.5, &x.nodes.6)));
+            }
+            RefNode::TimeunitsDeclaration(x) => found.push(match x {
+                TimeunitsDeclaration::Timeunit(x) => (
+                    time(&x.nodes.1),
+                    x.nodes.2.as_ref().and_then(|(_, p)| time(p)),
+                ),
+                TimeunitsDeclaration::Timeprecision(x) => (None, time(&x.nodes.1)),
+                TimeunitsDeclaration::TimeunitTimeprecision(x) => {
+                    (time(&x.nodes.1), time(&x.nodes.4))
+                }
+                TimeunitsDeclaration::TimeprecisionTimeunit(x) => {
+                    (time(&x.nodes.4), time(&x.nodes.1))
+                }
+            }),
+            _ => (),
+        }
+    }
+    found
+}
+
+/// Report conflicting time units and precisions in `files` and determine the timescale of the
+/// pickle: the given one, or the first time unit found with the finest precision found.
+pub fn normalize_timescales(
+    files: &[&ParsedFile],
+    timescale: Option<Timescale>,
+) -> Option<Timescale> {
+    let mut units = BTreeSet::new();
+    let mut precisions = BTreeSet::new();
+    let mut first_unit = None;
+    let mut report = vec![];
+    for pf in files {
+        for (unit, precision) in timescales(&pf.ast) {
+            let fmt = |t: Option<u64>| t.map_or("-".to_string(), format_time);
+            report.push(format!("{}/{} in {}", fmt(unit), fmt(precision), pf.path));
+            units.extend(unit);
+            precisions.extend(precision);
+            first_unit = first_unit.or(unit);
+        }
+    }
+    if units.len() > 1 || precisions.len() > 1 {
+        warn!(
+            "Conflicting time units and precisions:\n\t{}",
+            report.join("\n\t")
+        );
+    }
+    timescale.or_else(|| {
+        let precision = *precisions.iter().next()?;
+        let unit = first_unit.unwrap_or(precision).max(precision);
+        Some(Timescale { unit, precision })
+    })
+}
+
+/// The kind of a declaration.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnitKind {
+    Module,
+    Interface,
+    Package,
+    Program,
+    Checker,
+    Class,
+    Primitive,
+    Config,
+}
+
+impl From<&RefNode<'_>> for UnitKind {
+    fn from(id: &RefNode) -> Self {
+        match id {
+            RefNode::InterfaceIdentifier(_) => UnitKind::Interface,
+            RefNode::PackageIdentifier(_) => UnitKind::Package,
+            RefNode::ProgramIdentifier(_) => UnitKind::Program,
+            RefNode::CheckerIdentifier(_) => UnitKind::Checker,
+            RefNode::ClassIdentifier(_) => UnitKind::Class,
+            RefNode::UdpIdentifier(_) => UnitKind::Primitive,
+            RefNode::ConfigIdentifier(_) => UnitKind::Config,
+            _ => UnitKind::Module,
+        }
+    }
+}
+
+/// A renamed declaration.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RenameEntry {
+    // original name
+    pub name: String,
+    // name in the pickle
+    pub new_name: String,
+    // kind of the declaration
+    pub kind: UnitKind,
+    // file declaring it
+    pub file: String,
+}
+
+/// A range of lines in the pickle which stem from consecutive lines of a file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SourceMapEntry {
+    // first line in the pickle
+    pub pickle_line: usize,
+    // number of lines
+    pub lines: usize,
+    // file the lines stem from
+    pub file: String,
+    // line in the file
+    pub line: usize,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Manifest {
     // list of file bundles
@@ -959,12 +4291,117 @@ pub struct Manifest {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FileBundle {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub include_dirs: Vec<String>,
 
     #[serde(default)]
     pub export_incdirs: HashMap<String, Vec<String>>,
     pub defines: HashMap<String, Option<String>>,
     pub files: Vec<String>,
+
+    // renaming settings overriding the global ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_rename: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+}
+
+/// Renaming settings of a single file bundle.
+#[derive(Debug, Default, Clone)]
+pub struct BundleRenaming {
+    /// Name prefix, overriding the global one.
+    pub prefix: Option<String>,
+    /// Name suffix, overriding the global one.
+    pub suffix: Option<String>,
+    /// Declarations of the bundle which are excluded from re-naming.
+    pub exclude_rename: Vec<NamePattern>,
+    /// Declarations of the bundle which are excluded from the pickled sources.
+    pub exclude: Vec<NamePattern>,
+}
+
+/// A pattern for declaration names: either an exact name, a glob with `*`, `?` and `[...]`
+/// wildcards, or a regular expression prefixed with `re:`.
+#[derive(Debug, Clone)]
+pub struct NamePattern {
+    /// The pattern as given by the user.
+    pub pattern: String,
+    regex: Regex,
+}
+
+impl NamePattern {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let regex = match pattern.strip_prefix("re:") {
+            Some(re) => Regex::new(re),
+            None => {
+                let mut glob = String::from("^");
+                let mut in_class = false;
+                for c in pattern.chars() {
+                    match c {
+                        '*' if !in_class => glob.push_str(".*"),
+                        '?' if !in_class => glob.push('.'),
+                        '[' if !in_class => {
+                            in_class = true;
+                            glob.push('[');
+                        }
+                        '!' if in_class && glob.ends_with('[') => glob.push('^'),
+                        ']' if in_class => {
+                            in_class = false;
+                            glob.push(']');
+                        }
+                        '\\' | '[' | '&' | '~' if in_class => {
+                            glob.push_str(&regex::escape(&c.to_string()))
+                        }
+                        c if in_class => glob.push(c),
+                        c => glob.push_str(&regex::escape(&c.to_string())),
+                    }
+                }
+                glob.push('$');
+                Regex::new(&glob)
+            }
+        }
+        .with_context(|| format!("Invalid pattern `{}`", pattern))?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            regex,
+        })
+    }
+
+    /// Parse a list of patterns.
+    pub fn parse_all<S: AsRef<str>>(patterns: &[S]) -> Result<Vec<Self>> {
+        patterns.iter().map(|p| Self::new(p.as_ref())).collect()
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        self.regex.is_match(name)
+    }
+}
+
+// The options name patterns are given with, as reported for patterns which did not match.
+const EXCLUDE_RENAME: &str = "--exclude-rename";
+const EXCLUDE: &str = "--exclude";
+const BLACKBOX: &str = "--blackbox";
+const BUNDLE_EXCLUDE_RENAME: &str = "bundle exclude_rename";
+const BUNDLE_EXCLUDE: &str = "bundle exclude";
+
+// Check whether `name` matches any of `patterns` given with `option` and record the matching
+// patterns.
+fn match_patterns(
+    matched: &mut HashSet<(&'static str, String)>,
+    option: &'static str,
+    patterns: &[NamePattern],
+    name: &str,
+) -> bool {
+    let mut found = false;
+    for pattern in patterns.iter().filter(|p| p.is_match(name)) {
+        matched.insert((option, pattern.pattern.clone()));
+        found = true;
+    }
+    found
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -994,7 +4431,73 @@ impl LibraryBundle {
         files.push(f.to_string());
 
         // if so, parse the file and return the result (comments are always stripped).
-        parse_file(&f, &bundle_include_dirs, &bundle_defines, true)
+        parse_file(&f, &bundle_include_dirs, &bundle_defines, true, None, false)
+    }
+}
+
+impl LibraryBundle {
+    /// Parse the file of a substitution with the global include directories and defines. If the
+    /// file does not declare the substituted module, its top module is renamed to it.
+    pub fn load_substitute(&self, substitution: &Substitution) -> Result<ParsedFile> {
+        let Substitution { module, file } = substitution;
+        let include_dirs: Vec<_> = self.include_dirs.iter().map(Path::new).collect();
+        let defines = defines_to_sv_parser(&self.defines);
+        // Comments are stripped while pickling, if at all.
+        let pf = parse_file(file, &include_dirs, &defines, false, None, false)?;
+
+        // The replacement is the only module which is not instantiated within the file.
+        let mut instantiated = HashSet::new();
+        for node in &pf.ast {
+            if let RefNode::ModuleInstantiation(x) = node {
+                let id = unwrap_node!(x, SimpleIdentifier).unwrap();
+                instantiated.insert(get_identifier(&pf.ast, id).0);
+            }
+        }
+        let modules = unit_declarations(&pf.ast)
+            .into_iter()
+            .filter(|(decl, _)| blackbox_items(decl.clone()).is_some())
+            .map(|(_, id)| get_identifier(&pf.ast, id).0)
+            .collect::<Vec<_>>();
+        let tops = modules
+            .iter()
+            .filter(|m| !instantiated.contains(*m))
+            .collect::<Vec<_>>();
+        let name = match tops.as_slice() {
+            _ if modules.contains(module) => return Ok(pf),
+            [name] => *name,
+            _ => {
+                return Err(anyhow!(
+                    "{} must declare `{}` or a single top module to substitute it",
+                    file,
+                    module
+                ))
+            }
+        };
+        info!("Renaming `{}` in {} to `{}`", name, file, module);
+
+        // Rename the declaration and its end label, and parse the file again.
+        let mut source = pf.source.clone();
+        let mut locs = vec![];
+        for node in &pf.ast {
+            if let RefNode::ModuleIdentifier(_) = node {
+                let (id, loc) = get_identifier(&pf.ast, node);
+                if id == *name {
+                    locs.push(loc);
+                }
+            }
+        }
+        for loc in locs.iter().rev() {
+            source.replace_range(loc.offset..loc.offset + loc.len, module);
+        }
+        let pp = preprocess_str(&source, file, &defines, &include_dirs, false, false, 0, 0)
+            .with_context(|| format!("Failed to preprocess `{}`", file))?;
+        let syntax_tree = parse_pp(pp)?;
+        Ok(ParsedFile {
+            source,
+            ast: syntax_tree.0,
+            defines: syntax_tree.1,
+            ..pf
+        })
     }
 }
 
@@ -1008,6 +4511,171 @@ pub struct ParsedFile {
     pub ast: SyntaxTree,
     /// Internal defines
     pub defines: Defines,
+    /// Index of the file bundle the file belongs to, `None` for library files.
+    pub bundle: Option<usize>,
+    /// The partially preprocessed text of the file, which is emitted instead of `source`.
+    pub partial: Option<PartialSource>,
+    /// The included text to be replaced by `` `include `` directives.
+    pub includes: Vec<Include>,
+}
+
+/// Text in a preprocessed file which stems from its `` `include `` directives.
+#[derive(Debug)]
+pub struct Include {
+    /// Offset of the text in the preprocessed file.
+    pub offset: usize,
+    /// Length of the text in the preprocessed file.
+    pub len: usize,
+    /// The files included by the directives.
+    pub directives: Vec<PathBuf>,
+    /// All files the text stems from, including nested includes.
+    pub files: Vec<PathBuf>,
+}
+
+impl ParsedFile {
+    /// The file and line the preprocessed text at an offset stems from, for messages. Falls back
+    /// to the file itself and the line in the preprocessed text.
+    pub fn source_line(&self, offset: usize) -> (String, usize) {
+        let origin = match &self.partial {
+            Some(partial) => partial.origin(offset),
+            None => Some(offset),
+        };
+        let line = origin
+            .and_then(|offset| self.origin(offset))
+            .and_then(|(path, origin)| {
+                let text = std::fs::read_to_string(path).ok()?;
+                let line = text.get(..origin)?.matches('\n').count() + 1;
+                Some((path.display().to_string(), line))
+            });
+        line.unwrap_or_else(|| {
+            let line = self.source[..offset.min(self.source.len())]
+                .matches('\n')
+                .count()
+                + 1;
+            (self.path.clone(), line)
+        })
+    }
+
+    /// The file and offset the emitted text at an offset stems from.
+    pub fn origin(&self, offset: usize) -> Option<(&Path, usize)> {
+        match &self.partial {
+            Some(partial) => partial.text_origin(offset),
+            None => {
+                let locate = Locate {
+                    offset,
+                    line: 0,
+                    len: 1,
+                };
+                let (path, origin) = self.ast.get_origin(&locate)?;
+                Some((path.as_path(), origin))
+            }
+        }
+    }
+}
+
+/// The partially preprocessed text of a file, see `Unifdef`.
+pub struct PartialSource {
+    /// The text with only the given conditionals resolved and the includes inlined.
+    pub text: String,
+    /// Runs `(start, end, origin)` of the preprocessed text which stem from `text`.
+    pub origins: Vec<(usize, usize, usize)>,
+    /// Runs `(offset, file, origin)` of `text` and the files they stem from.
+    pub text_origins: Vec<(usize, PathBuf, usize)>,
+}
+
+impl PartialSource {
+    pub fn new(text: PartialText, pp: &PreprocessedText, path: &Path) -> Self {
+        let mut origins: Vec<(usize, usize, usize)> = vec![];
+        for pos in 0..pp.text().len() {
+            let origin = match pp.origin(pos) {
+                Some((p, origin)) if p == path => origin,
+                _ => continue,
+            };
+            match origins.last_mut() {
+                Some((start, end, o)) if *end == pos && *o + pos - *start == origin => *end += 1,
+                _ => origins.push((pos, pos + 1, origin)),
+            }
+        }
+        Self {
+            text: text.text,
+            origins,
+            text_origins: text.origins,
+        }
+    }
+
+    /// The file and offset the text at an offset stems from.
+    pub fn text_origin(&self, pos: usize) -> Option<(&Path, usize)> {
+        let i = self
+            .text_origins
+            .partition_point(|(start, _, _)| *start <= pos);
+        let (start, path, origin) = &self.text_origins[i.checked_sub(1)?];
+        Some((path.as_path(), origin + pos - start))
+    }
+
+    // Map an offset in the preprocessed text to the partially preprocessed one.
+    fn origin(&self, pos: usize) -> Option<usize> {
+        let i = self.origins.partition_point(|&(start, _, _)| start <= pos);
+        let (start, end, origin) = self.origins[i.checked_sub(1)?];
+        (pos < end).then_some(origin + pos - start)
+    }
+
+    /// Map a replacement table of the preprocessed text to the partially preprocessed one.
+    pub fn map(
+        &self,
+        file: &str,
+        table: Vec<(usize, usize, String)>,
+    ) -> Vec<(usize, usize, String)> {
+        table
+            .into_iter()
+            .filter_map(|(offset, len, repl)| {
+                let range = if len == 0 {
+                    // Insertions go after the preceding text.
+                    match offset.checked_sub(1) {
+                        Some(pos) => self.origin(pos).map(|o| (o + 1, o + 1)),
+                        None => Some((0, 0)),
+                    }
+                } else {
+                    self.origin(offset)
+                        .zip(self.origin(offset + len - 1))
+                        .filter(|(start, end)| start <= end)
+                        .map(|(start, end)| (start, end + 1))
+                };
+                match range {
+                    Some((start, end)) => Some((start, end - start, repl)),
+                    None => {
+                        warn!(
+                            "Cannot map replacement `{}` in {} to the partially preprocessed text",
+                            repl, file
+                        );
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// The ranges of the text which are not part of the preprocessed text, such as conditional
+    /// branches which were not taken and unused macro definitions.
+    pub fn unparsed(&self) -> Vec<(usize, usize)> {
+        let mut covered: Vec<_> = self
+            .origins
+            .iter()
+            .map(|&(start, end, origin)| (origin, origin + end - start))
+            .collect();
+        covered.sort();
+        let mut ranges = vec![];
+        let mut pos = 0;
+        for (start, end) in covered {
+            if start > pos {
+                ranges.push((pos, start));
+            }
+            pos = pos.max(end);
+        }
+        if pos < self.text.len() {
+            ranges.push((pos, self.text.len()));
+        }
+        ranges
+    }
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -8,10 +8,10 @@
 extern crate log;
 
 use anyhow::Result;
-use clap::{Arg, ArgAction, Command};
+use clap::{Arg, ArgAction, ArgGroup, Command};
 use log::LevelFilter;
 use simple_logger::SimpleLogger;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io;
 use std::io::{BufRead, BufReader, BufWriter, Write};
@@ -38,7 +38,7 @@ fn main() -> Result<()> {
                 .short('e')
                 .long("exclude-rename")
                 .value_name("MODULE|INTERFACE|PACKAGE")
-                .help("Add module, interface, package which should not be renamed")
+                .help("Add module, interface, package which should not be renamed. Accepts globs (`tc_*`) and regular expressions (`re:^tc_.*`)")
                 .action(ArgAction::Append)
                 .num_args(1),
         )
@@ -46,7 +46,7 @@ fn main() -> Result<()> {
             Arg::new("exclude")
                 .long("exclude")
                 .value_name("MODULE|INTERFACE|PACKAGE")
-                .help("Do not include module, interface, package in the pickled file list")
+                .help("Do not include module, interface, package in the pickled file list. Accepts globs (`tb_*`) and regular expressions (`re:^tb_.*`)")
                 .action(ArgAction::Append)
                 .num_args(1),
         )
@@ -73,6 +73,30 @@ fn main() -> Result<()> {
                 .action(ArgAction::Append)
                 .num_args(1),
         )
+        .arg(
+            Arg::new("undef")
+                .short('U')
+                .value_name("DEFINE")
+                .help("Treat a preprocessor macro as undefined. Requires --unifdef.")
+                .action(ArgAction::Append)
+                .requires("unifdef")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("unifdef")
+                .long("unifdef")
+                .help("Only resolve the conditionals on the macros given with -D and -U, keeping all other conditionals and macro usages as written. Implies --keep_defines.")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("keep_includes")
+                .long("keep_includes")
+                .value_name("DIR")
+                .help("Keep `include directives instead of inlining the included files, which are written to DIR")
+                .conflicts_with("unifdef")
+                .num_args(1),
+        )
         .arg(
             Arg::new("suffix")
                 .short('s')
@@ -111,6 +135,28 @@ fn main() -> Result<()> {
                 .num_args(0)
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("strip_translate_off")
+                .long("strip_translate_off")
+                .help("Strip code between translate_off and translate_on markers, e.g., `// synopsys translate_off`, from the output")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strip_assertions")
+                .long("strip_assertions")
+                .help("Strip concurrent assertions, assumptions, cover statements, property and sequence declarations and covergroups from the output")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strip_immediate_assertions")
+                .long("strip_immediate_assertions")
+                .help("Strip immediate assertions as well. Requires --strip_assertions.")
+                .num_args(0)
+                .requires("strip_assertions")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("INPUT")
                 .help("The input files to compile")
@@ -132,6 +178,32 @@ fn main() -> Result<()> {
                 .help("Write output to file")
                 .num_args(1),
         )
+        .arg(
+            Arg::new("split_output")
+                .long("split_output")
+                .value_name("DIR")
+                .help("Write the pickled sources to separate files in DIR and a file list in compile order to the output")
+                .conflicts_with("source_map")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("split_by")
+                .long("split_by")
+                .value_name("MODE")
+                .help("Write one file per input file or per module, interface, package, etc. Requires --split_output.")
+                .value_parser(["file", "unit"])
+                .default_value("file")
+                .requires("split_output")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("blackbox")
+                .long("blackbox")
+                .value_name("MODULE")
+                .help("Replace the body of a module with a stub keeping only its parameters and ports. Accepts globs (`*_macro`) and regular expressions (`re:^sram_.*`)")
+                .action(ArgAction::Append)
+                .num_args(1),
+        )
         .arg(
             Arg::new("library_file")
                 .long("library-file")
@@ -160,7 +232,32 @@ fn main() -> Result<()> {
             Arg::new("top_module")
                 .long("top")
                 .value_name("TOP_MODULE")
-                .help("Top module, strips all unneeded files. May be incompatible with `--propagate_defines`.")
+                .help("Top module, strips all unneeded modules. May be repeated for multiple top modules. May be incompatible with `--propagate_defines`.")
+                .action(ArgAction::Append)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("top_wrapper")
+                .long("top_wrapper")
+                .help("Append a module under the original name of each renamed top module, which instantiates it with the same parameters and ports. Requires --top.")
+                .requires("top_module")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("param")
+                .long("param")
+                .value_name("MODULE.NAME=VALUE")
+                .help("Replace the default value of a parameter of a module in the pickle. May be repeated.")
+                .action(ArgAction::Append)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("substitute")
+                .long("substitute")
+                .value_name("MODULE=FILE")
+                .help("Replace the declaration of a module with the one in FILE, renaming it if FILE declares a single module under another name. Its ports must match.")
+                .action(ArgAction::Append)
                 .num_args(1),
         )
         .arg(
@@ -184,6 +281,22 @@ fn main() -> Result<()> {
                 .num_args(0)
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("rename_defines")
+                .long("rename_defines")
+                .help("Apply the prefix and suffix to the names of the kept `define macros. Requires --keep_defines or --unifdef.")
+                .num_args(0)
+                .requires("kept_defines")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("external_define")
+                .long("external_define")
+                .value_name("MACRO")
+                .help("Add `define macro which should not be renamed, e.g. `SYNTHESIS`. Accepts globs and regular expressions (`re:^...`)")
+                .action(ArgAction::Append)
+                .num_args(1),
+        )
         .arg(
             Arg::new("propagate_defines")
                 .long("propagate_defines")
@@ -206,6 +319,104 @@ fn main() -> Result<()> {
                 .num_args(0)
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("normalize_timeunits")
+                .long("normalize_timeunits")
+                .value_name("UNIT/PRECISION")
+                .help("Replace all timeunits and `timescale directives by a single `timescale, reporting conflicts. Defaults to the finest precision found.")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("auto")
+                .conflicts_with("keep_timeunits"),
+        )
+        .arg(
+            Arg::new("sort_files")
+                .long("sort_files")
+                .help("Emit the pickled files and manifest sources in compile order, i.e., dependencies first.")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("duplicates")
+                .long("duplicates")
+                .value_name("POLICY")
+                .help("How to handle modules, interfaces, packages declared multiple times.")
+                .value_parser(["error", "warn", "keep-first", "keep-last"])
+                .default_value("warn")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("localize_imports")
+                .long("localize_imports")
+                .help("Move global package imports into the headers of the modules, interfaces, programs and packages declared after them.")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("isolate_directives")
+                .long("isolate_directives")
+                .help("Restore the default state of directives such as `default_nettype and `timescale after each pickled file.")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("bundle_namespaces")
+                .long("bundle_namespaces")
+                .help("Rename each file bundle into its own namespace, prefixing its names with `PREFIX` and the bundle name.")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("rename_map")
+                .long("rename_map")
+                .value_name("FILE")
+                .help("Read explicit new names for modules, interfaces, packages from a JSON or TOML map, overriding prefix and suffix")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("line_directives")
+                .long("line_directives")
+                .help("Emit `line directives such that tool messages refer to the original files")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("source_map")
+                .long("source_map")
+                .value_name("FILE")
+                .help("Output a JSON-encoded map from the lines of the pickle to the original files to FILE")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("write_rename_map")
+                .long("write_rename_map")
+                .value_name("FILE")
+                .help("Output the JSON-encoded new names of all renamed declarations to FILE")
+                .num_args(1),
+        )
+        .group(
+            ArgGroup::new("kept_defines")
+                .args(["keep_defines", "unifdef"])
+                .multiple(true),
+        )
+        .subcommand(
+            Command::new("resolve")
+                .about("Resolve a line of a pickle to the file and line it stems from")
+                .arg(
+                    Arg::new("source_map")
+                        .value_name("SOURCE_MAP")
+                        .help("The source map written with --source_map")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("line")
+                        .value_name("LINE")
+                        .help("The line in the pickle")
+                        .value_parser(clap::value_parser!(usize))
+                        .required(true),
+                ),
+        )
+        .args_conflicts_with_subcommands(true)
         .get_matches();
 
     let logger_level = matches.get_count("v");
@@ -222,6 +433,16 @@ fn main() -> Result<()> {
         .init()
         .unwrap();
 
+    // Resolve a line of a pickle.
+    if let Some(matches) = matches.subcommand_matches("resolve") {
+        let (file, line) = resolve_source_map(
+            matches.get_one::<String>("source_map").unwrap(),
+            *matches.get_one::<usize>("line").unwrap(),
+        )?;
+        println!("{}:{}", file, line);
+        return Ok(());
+    }
+
     let mut file_list = Vec::new();
 
     // Handle user defines.
@@ -326,7 +547,7 @@ fn main() -> Result<()> {
         });
         let lines = BufReader::new(file).lines();
 
-        let proper_lines: Vec<String> = lines.filter_map(|x| x.ok()).collect();
+        let proper_lines: Vec<String> = lines.map_while(Result::ok).collect();
 
         all_files.extend(proper_lines);
     }
@@ -336,7 +557,6 @@ fn main() -> Result<()> {
 
     let stdin_files = all_files
         .into_iter()
-        .map(String::from)
         .filter_map(|file_str| {
             let split_str = file_str.splitn(3, '+').collect::<Vec<_>>();
             if split_str.len() > 1 {
@@ -371,29 +591,103 @@ fn main() -> Result<()> {
     stdin_defines.extend(defines);
 
     file_list.push(FileBundle {
+        name: None,
         include_dirs: stdin_incdirs.clone(),
         export_incdirs: HashMap::new(),
         defines: stdin_defines.clone(),
         files: stdin_files,
+        prefix: None,
+        suffix: None,
+        exclude_rename: vec![],
+        exclude: vec![],
     });
 
-    let (mut exclude_rename, mut exclude) = (HashSet::new(), HashSet::new());
-    exclude_rename.extend(
-        matches
+    let exclude_rename = NamePattern::parse_all(
+        &matches
             .get_many::<String>("exclude_rename")
             .into_iter()
-            .flatten(),
-    );
-    exclude.extend(matches.get_many::<String>("exclude").into_iter().flatten());
+            .flatten()
+            .collect::<Vec<_>>(),
+    )?;
+    let exclude = NamePattern::parse_all(
+        &matches
+            .get_many::<String>("exclude")
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>(),
+    )?;
+
+    let blackbox = NamePattern::parse_all(
+        &matches
+            .get_many::<String>("blackbox")
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>(),
+    )?;
+
+    let external_defines = NamePattern::parse_all(
+        &matches
+            .get_many::<String>("external_define")
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>(),
+    )?;
+
+    let param_overrides = matches
+        .get_many::<String>("param")
+        .into_iter()
+        .flatten()
+        .map(|s| s.parse())
+        .collect::<Result<Vec<ParamOverride>>>()?;
+
+    let substitutions = matches
+        .get_many::<String>("substitute")
+        .into_iter()
+        .flatten()
+        .map(|s| s.parse())
+        .collect::<Result<Vec<Substitution>>>()?;
+
+    let timeunits = match matches.get_one::<String>("normalize_timeunits") {
+        Some(s) if s == "auto" => TimeunitMode::Normalize(None),
+        Some(s) => TimeunitMode::Normalize(Some(s.parse()?)),
+        None if matches.get_flag("keep_timeunits") => TimeunitMode::Keep,
+        None => TimeunitMode::Remove,
+    };
 
     let strip_comments = matches.get_flag("strip_comments");
+    let strip_translate_off = matches.get_flag("strip_translate_off");
+
+    let duplicate_policy = match matches.get_one::<String>("duplicates").unwrap().as_str() {
+        "error" => DuplicatePolicy::Error,
+        "keep-first" => DuplicatePolicy::KeepFirst,
+        "keep-last" => DuplicatePolicy::KeepLast,
+        _ => DuplicatePolicy::Warn,
+    };
+
+    let top_modules: Vec<_> = matches
+        .get_many::<String>("top_module")
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let undefines: Vec<String> = matches
+        .get_many::<String>("undef")
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+    let unifdef = matches.get_flag("unifdef");
 
     let syntax_trees = build_syntax_tree(
         &file_list,
-        strip_comments,
+        // The markers are comments, so these are stripped while pickling instead. The same goes
+        // for comments in partially preprocessed files.
+        strip_comments && !strip_translate_off && !unifdef,
         matches.get_flag("ignore_unparseable"),
         matches.get_flag("propagate_defines"),
         matches.get_flag("sequential"),
+        unifdef.then_some(undefines.as_slice()),
+        matches.contains_id("keep_includes"),
     )?;
 
     let out = match matches.get_one::<String>("output") {
@@ -422,23 +716,68 @@ fn main() -> Result<()> {
     }
 
     let pickle = do_pickle(
-        matches.get_one::<String>("prefix"),
-        matches.get_one::<String>("suffix"),
-        exclude_rename,
-        exclude,
         library_bundle,
         syntax_trees,
         out,
-        matches.get_one::<String>("top_module"),
-        matches.get_flag("keep_defines"),
-        matches.get_flag("propagate_defines"),
-        !matches.get_flag("keep_timeunits"),
+        PickleOptions {
+            prefix: matches.get_one::<String>("prefix"),
+            suffix: matches.get_one::<String>("suffix"),
+            exclude_rename,
+            exclude,
+            top_modules: top_modules.clone(),
+            // The macro usages are kept, so are their definitions.
+            keep_defines: matches.get_flag("keep_defines") || unifdef,
+            propagate_defines: matches.get_flag("propagate_defines"),
+            timeunits,
+            sort_files: matches.get_flag("sort_files"),
+            duplicate_policy,
+            bundles: bundle_renaming(
+                &file_list,
+                matches.get_one::<String>("prefix"),
+                matches.get_flag("bundle_namespaces"),
+            )?,
+            bundle_namespaces: matches.get_flag("bundle_namespaces"),
+            rename_map: match matches.get_one::<String>("rename_map") {
+                Some(file) => read_rename_map(file)?,
+                None => HashMap::new(),
+            },
+            rename_defines: matches.get_flag("rename_defines"),
+            external_defines,
+            localize_imports: matches.get_flag("localize_imports"),
+            isolate_directives: matches.get_flag("isolate_directives"),
+            strip_comments,
+            strip_translate_off,
+            strip_assertions: matches.get_flag("strip_assertions"),
+            strip_immediate_assertions: matches.get_flag("strip_immediate_assertions"),
+            include_dir: matches.get_one::<String>("keep_includes").map(Path::new),
+            line_directives: matches.get_flag("line_directives"),
+            source_map: matches.contains_id("source_map"),
+            split: matches.get_one::<String>("split_output").map(|dir| {
+                let mode = match matches.get_one::<String>("split_by").unwrap().as_str() {
+                    "unit" => SplitMode::Units,
+                    _ => SplitMode::Files,
+                };
+                (Path::new(dir), mode)
+            }),
+            blackbox,
+            top_wrapper: matches.get_flag("top_wrapper"),
+            param_overrides,
+            substitutions,
+        },
     )?;
 
     if let Some(graph_file) = matches.get_one::<String>("graph_file") {
         write_dot_graph(&pickle, graph_file)?;
     }
 
+    if let Some(rename_map_file) = matches.get_one::<String>("write_rename_map") {
+        write_rename_map(&pickle, rename_map_file)?;
+    }
+
+    if let Some(source_map_file) = matches.get_one::<String>("source_map") {
+        write_source_map(&pickle, source_map_file)?;
+    }
+
     // if the user requested a manifest we need to compute the information and output it in json
     // form
     if let Some(manifest_file) = matches.get_one::<String>("manifest") {
@@ -448,7 +787,7 @@ fn main() -> Result<()> {
             file_list,
             stdin_incdirs,
             stdin_defines,
-            matches.get_one::<String>("top_module"),
+            top_modules,
         )?;
     }
 
@@ -180,6 +180,7 @@ impl Printer {
     }
 
     #[cfg_attr(tarpaulin, skip)]
+    #[allow(clippy::too_many_arguments)]
     fn print_pretty(
         &mut self,
         src: &str,
@@ -0,0 +1,22 @@
+module assertions (
+  input logic clk,
+  input logic a,
+  input logic b
+);
+  property p_a_b;
+    @(posedge clk) a |-> b;
+  endproperty
+
+  a_b: assert property (p_a_b) else $error("b did not follow a");
+  cover property (@(posedge clk) a ##1 b);
+
+  covergroup cg_a @(posedge clk);
+    coverpoint a;
+  endgroup
+  cg_a i_cg_a = new;
+
+  always_ff @(posedge clk) begin
+    assert (a || b);
+    i_cg_a.sample();
+  end
+endmodule
@@ -0,0 +1,9 @@
+module assertions_trailing (
+  input logic clk,
+  input logic a
+);
+  logic q;
+  assign q = a;
+  assume property (@(posedge clk) a);
+  cover property (@(posedge clk) q);
+endmodule
@@ -0,0 +1,21 @@
+module bind_dut #(
+  parameter int Width = 1
+) (
+  input logic clk
+);
+  logic [Width-1:0] sig;
+endmodule
+
+module bind_chk (
+  input logic clk
+);
+endmodule
+
+module bind_tb;
+  logic clk;
+  bind_dut i_dut (.clk);
+  defparam bind_tb.i_dut.Width = 2;
+  initial $display($root.bind_tb.i_dut.sig);
+endmodule
+
+bind bind_dut bind_chk i_chk (.clk);
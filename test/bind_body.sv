@@ -0,0 +1,15 @@
+module bind_body_dut (
+  input logic clk
+);
+endmodule
+
+module bind_body_chk (
+  input logic clk
+);
+endmodule
+
+module bind_body_tb;
+  logic clk;
+  bind_body_dut i_dut (.clk);
+  bind bind_body_dut bind_body_chk i_chk (.clk);
+endmodule
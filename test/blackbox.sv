@@ -0,0 +1,38 @@
+package sram_pkg;
+  typedef logic [7:0] word_t;
+endpackage
+
+module sram_macro #(
+  parameter int unsigned Depth = 64
+) (
+  input  logic               clk_i,
+  input  sram_pkg::word_t    wdata_i,
+  output sram_pkg::word_t    rdata_o
+);
+  localparam int unsigned AddrWidth = $clog2(Depth);
+  sram_pkg::word_t mem [Depth];
+  always_ff @(posedge clk_i) begin
+    rdata_o <= mem[0];
+    mem[0] <= wdata_i;
+  end
+  sram_cell i_cell ();
+endmodule
+
+module sram_legacy (clk_i, rdata_o);
+  parameter Width = 8;
+  input clk_i;
+  output [Width-1:0] rdata_o;
+  reg [Width-1:0] rdata_o;
+  always @(posedge clk_i) rdata_o <= 0;
+endmodule
+
+module sram_cell;
+endmodule
+
+module top (
+  input  logic            clk_i,
+  output sram_pkg::word_t rdata_o
+);
+  sram_macro #(.Depth(32)) i_sram (.clk_i, .wdata_i('0), .rdata_o);
+  sram_legacy i_legacy (.clk_i, .rdata_o());
+endmodule
@@ -0,0 +1,3 @@
+module bundle_top;
+  ip_core i_ip_core ();
+endmodule
@@ -0,0 +1,8 @@
+package ip_pkg;
+  localparam int Version = 1;
+endpackage
+
+module ip_core import ip_pkg::*; #(
+  parameter int V = Version
+)();
+endmodule
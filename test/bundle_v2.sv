@@ -0,0 +1,8 @@
+package ip_pkg;
+  localparam int Version = 2;
+endpackage
+
+module ip_core #(
+  parameter int V = ip_pkg::Version
+)();
+endmodule
@@ -0,0 +1,7 @@
+`timescale 1ns/1ps
+`default_nettype none
+
+module directives (
+  input wire a
+);
+endmodule
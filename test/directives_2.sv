@@ -0,0 +1,3 @@
+`celldefine
+module directives_2;
+endmodule
@@ -0,0 +1,14 @@
+package global_pkg;
+  typedef logic [3:0] nibble_t;
+endpackage
+
+import global_pkg::*;
+
+module global_import (
+  input nibble_t a
+);
+endmodule
+
+package global_user_pkg;
+  localparam nibble_t Zero = '0;
+endpackage
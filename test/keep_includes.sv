@@ -0,0 +1,8 @@
+`include "include.svh"
+module keep_includes (
+  input logic clk_i,
+  input logic rst_ni
+);
+  logic q;
+  `FF(q, '0, '0)
+endmodule
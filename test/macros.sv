@@ -0,0 +1,14 @@
+`define WIDTH 8
+`define DWIDTH (`WIDTH*2)
+`ifndef SYNTHESIS
+`define SYNTHESIS
+`endif
+
+module macros (
+  input  logic [`DWIDTH-1:0] d,
+  output logic [`DWIDTH-1:0] q
+);
+  assign q = d;
+endmodule
+
+`undef WIDTH
@@ -0,0 +1,5 @@
+`define WIDTH 16
+
+module macros_2;
+  logic [`WIDTH-1:0] data;
+endmodule
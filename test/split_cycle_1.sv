@@ -0,0 +1,7 @@
+package split_cycle_a_pkg;
+  localparam int A = 1;
+endpackage
+
+module split_cycle_top;
+  import split_cycle_b_pkg::*;
+endmodule
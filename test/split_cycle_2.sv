@@ -0,0 +1,3 @@
+package split_cycle_b_pkg;
+  localparam int B = split_cycle_a_pkg::A;
+endpackage
@@ -0,0 +1,13 @@
+module sram_model #(
+  parameter int unsigned Depth = 64
+) (
+  input  logic       clk_i,
+  input  logic [7:0] wdata_i,
+  output logic [7:0] rdata_o
+);
+  logic [7:0] mem;
+endmodule
+
+module top;
+  sram_model i_sram (.clk_i(), .wdata_i(), .rdata_o());
+endmodule
@@ -0,0 +1,18 @@
+module tc_sram_fpga #(
+  parameter int unsigned Depth = 64
+) (
+  input  logic       clk_i,
+  input  logic [7:0] wdata_i,
+  output logic [7:0] rdata_o
+);
+  xilinx_bram #(.Depth(Depth)) i_bram (.clk_i, .wdata_i, .rdata_o);
+endmodule : tc_sram_fpga
+
+module xilinx_bram #(
+  parameter int unsigned Depth = 64
+) (
+  input  logic       clk_i,
+  input  logic [7:0] wdata_i,
+  output logic [7:0] rdata_o
+);
+endmodule
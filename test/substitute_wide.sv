@@ -0,0 +1,6 @@
+module sram_wide (
+  input  logic        clk_i,
+  input  logic [7:0]  wdata_i,
+  output logic [15:0] rdata_o
+);
+endmodule
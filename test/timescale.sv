@@ -0,0 +1,4 @@
+`timescale 1ns/1ps
+
+module timescale_1;
+endmodule
@@ -0,0 +1,4 @@
+module timescale_2;
+  timeunit 1ns;
+  timeprecision 100fs;
+endmodule
@@ -0,0 +1,15 @@
+package top_pkg;
+  typedef logic [7:0] data_t;
+endpackage
+
+module top #(
+  parameter int unsigned Width = 8,
+  parameter type         data_t = top_pkg::data_t,
+  localparam int unsigned Depth = 2 * Width
+) (
+  input  logic           clk_i,
+  input  top_pkg::data_t data_i,
+  output data_t          data_o
+);
+  always_ff @(posedge clk_i) data_o <= data_i;
+endmodule
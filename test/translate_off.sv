@@ -0,0 +1,12 @@
+`include "translate_off.svh"
+module strip_regions (
+  input logic clk
+);
+  // synopsys translate_off
+  initial $display("simulation only");
+  /* pragma translate_off */
+  initial $display("repeated marker");
+  // pragma translate_on
+  logic synthesized;
+  // synopsys translate_on
+endmodule
@@ -0,0 +1,38 @@
+`include "include.svh"
+
+`define DW (`WIDTH*2)
+`define SEL \
+`ifdef TARGET_ASIC \
+  1'b1 \
+`else \
+  1'b0 \
+`endif
+
+module unifdef_leaf #(parameter int Width = 1) (
+  input logic clk_i,
+  input logic rst_ni
+);
+  logic [Width-1:0] q;
+  `FF(q, '0, '0)
+  logic [`DW-1:0] w;
+  localparam bit Sel = `SEL;
+endmodule
+
+module unifdef_top (
+  input logic clk_i,
+  input logic rst_ni
+);
+`ifdef TARGET_ASIC
+  unifdef_leaf i_asic (.*);
+`else
+  unifdef_leaf i_fpga (.*);
+`endif
+
+`ifdef VERILATOR
+  unifdef_leaf #(.Width(`WIDTH)) i_verilator (.*);
+`elsif TARGET_ASIC
+  unifdef_leaf #(.Width(2)) i_asic_2 (.*);
+`else
+  unifdef_leaf #(.Width(3)) i_fpga_2 (.*);
+`endif
+endmodule
@@ -0,0 +1,8 @@
+`include "unit_header.svh"
+
+module unit_header (
+  input  byte_t d,
+  output byte_t q
+);
+  assign q = d;
+endmodule
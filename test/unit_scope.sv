@@ -0,0 +1,15 @@
+typedef logic [7:0] byte_t;
+localparam int unsigned Depth = 4;
+
+function automatic byte_t incr(byte_t x);
+  return x + 1;
+endfunction
+
+module unit_scope (
+  input  byte_t [Depth-1:0] d,
+  output byte_t [Depth-1:0] q
+);
+  for (genvar i = 0; i < Depth; i++) begin : gen_incr
+    assign q[i] = incr(d[i]);
+  end
+endmodule
@@ -0,0 +1,16 @@
+localparam int W = 8;
+
+module unit_scope_sub #(
+  parameter int W = 2
+) (
+  output logic [W-1:0] q
+);
+  assign q = '0;
+endmodule
+
+module unit_scope_top (
+  output logic [W-1:0] q
+);
+  unit_scope_sub #(.W(4)) i_sub (.q(q[3:0]));
+  assign q[W-1:4] = '0;
+endmodule
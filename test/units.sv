@@ -0,0 +1,42 @@
+class base_cls;
+  int a;
+endclass
+
+class my_cls extends base_cls;
+  base_cls b;
+  function new();
+    b = new;
+  endfunction
+endclass : my_cls
+
+interface class ifc_cls;
+endclass
+
+checker my_chk (logic a);
+endchecker : my_chk
+
+primitive my_udp (output o, input a, input b);
+  table
+    0 0 : 0;
+    1 ? : 1;
+    ? 1 : 1;
+  endtable
+endprimitive
+
+program my_prog (input logic clk);
+  my_cls c;
+  initial c = new();
+endprogram : my_prog
+
+module top;
+  logic a, b, o, clk;
+  my_cls c;
+  my_udp u0 (o, a, b);
+  my_prog p0 (clk);
+  my_chk chk0 (a);
+endmodule
+
+config my_cfg;
+  design work.top;
+  cell my_prog use work.my_prog;
+endconfig
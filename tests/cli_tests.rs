@@ -64,6 +64,802 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_top_strips_modules() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv").arg("--top").arg("module_4");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module module_1;"))
+            .stdout(predicate::str::contains("module module_2"))
+            .stdout(predicate::str::contains("module_3").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_tops() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--top")
+            .arg("module_2")
+            .arg("--top")
+            .arg("module_3");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module module_2"))
+            .stdout(predicate::str::contains("module_3"))
+            .stdout(predicate::str::contains("module_1").not())
+            .stdout(predicate::str::contains("module_4").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_files() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/package_import_2.sv")
+            .arg("test/package.sv")
+            .arg("--sort_files");
+
+        cmd.assert().success().stdout(
+            predicate::str::is_match(r"(?s)package blub_pkg;.*module test import").unwrap(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicates() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("test/instantiation.sv")
+            .arg("--duplicates")
+            .arg("error");
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "`module_1` in test/modules.sv, test/instantiation.sv",
+        ));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("test/instantiation.sv")
+            .arg("--duplicates")
+            .arg("keep-first");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module_2 i_module_2"))
+            .stdout(predicate::str::contains("module_0 mod").not());
+
+        // The same file listed twice does not declare duplicates.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("test/modules.sv")
+            .arg("--duplicates")
+            .arg("keep-first");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module module_1").count(1))
+            .stderr(predicate::str::contains("Duplicate declarations").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bundle_namespaces() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("-f")
+            .arg("test/bundles.json")
+            .arg("--bundle_namespaces")
+            .arg("--duplicates")
+            .arg("error");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "module ip_v1_ip_core import ip_v1_ip_pkg::*;",
+            ))
+            .stdout(predicate::str::contains("V = ip_v2_ip_pkg::Version"))
+            .stdout(predicate::str::contains("ip_v2_ip_core i_ip_core"));
+
+        // A top module declared in several bundles has to be given by its unit name.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("-f")
+            .arg("test/bundles.json")
+            .arg("--bundle_namespaces")
+            .args(["--top", "ip_core"]);
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Top module `ip_core` is declared in several bundles, use one of `ip_v1_ip_core`, \
+             `ip_v2_ip_core`",
+        ));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("-f")
+            .arg("test/bundles.json")
+            .arg("--bundle_namespaces")
+            .args(["--top", "ip_v1_ip_core"]);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module ip_v1_ip_core"))
+            .stdout(predicate::str::contains("module ip_v2_ip_core").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bundle_renaming() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("-f")
+            .arg("test/bundles_rename.json")
+            .arg("-p")
+            .arg("g_")
+            .arg("--duplicates")
+            .arg("error");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("package ip_pkg;"))
+            .stdout(predicate::str::contains(
+                "module v1_ip_core import ip_pkg::*;",
+            ))
+            .stdout(predicate::str::contains("module g_bundle_top;"))
+            .stdout(predicate::str::contains("v1_ip_core i_ip_core"))
+            .stdout(predicate::str::contains("Version = 2").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_map() -> Result<()> {
+        let rename_map = assert_fs::NamedTempFile::new("rename_map.json")?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("-p")
+            .arg("p_")
+            .arg("--rename_map")
+            .arg("test/rename_map.toml")
+            .arg("--write_rename_map")
+            .arg(rename_map.path());
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("renamed_module i_module_2();"))
+            .stdout(predicate::str::contains("module p_module_1;"));
+
+        let written = std::fs::read_to_string(rename_map.path())?;
+        assert!(written.contains(r#""new_name": "renamed_module""#));
+        assert!(written.contains(r#""kind": "module""#));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_patterns() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("-p")
+            .arg("p_")
+            .arg("-e")
+            .arg("module_[12]")
+            .arg("--exclude")
+            .arg("re:^module_(3|9)$")
+            .arg("--exclude")
+            .arg("unused_*")
+            .arg("--blackbox")
+            .arg("sram_*");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module module_1;"))
+            .stdout(predicate::str::contains("module module_2"))
+            .stdout(predicate::str::contains("module_3").not())
+            .stdout(predicate::str::contains("module p_module_4"))
+            .stderr(predicate::str::contains("--exclude `unused_*`"))
+            .stderr(predicate::str::contains("--blackbox `sram_*`"))
+            .stderr(predicate::str::contains("module_[12]").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_units() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/units.sv").arg("-p").arg("p_");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "class p_my_cls extends p_base_cls;",
+            ))
+            .stdout(predicate::str::contains("interface class p_ifc_cls;"))
+            .stdout(predicate::str::contains("checker p_my_chk"))
+            .stdout(predicate::str::contains("primitive p_my_udp"))
+            .stdout(predicate::str::contains("program p_my_prog"))
+            .stdout(predicate::str::contains("config p_my_cfg;"))
+            .stdout(predicate::str::contains("p_my_udp u0"));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/units.sv").arg("--top").arg("my_prog");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("class my_cls extends base_cls;"))
+            .stdout(predicate::str::contains("program my_prog"))
+            .stdout(predicate::str::contains("primitive my_udp").not())
+            .stdout(predicate::str::contains("module top").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_defines() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/macros.sv")
+            .arg("-p")
+            .arg("p_")
+            .arg("--keep_defines")
+            .arg("--rename_defines")
+            .arg("--external_define")
+            .arg("SYNTHESIS");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("`define p_WIDTH 8"))
+            .stdout(predicate::str::contains("`define p_DWIDTH (`p_WIDTH*2)"))
+            .stdout(predicate::str::contains("`define SYNTHESIS"))
+            .stdout(predicate::str::contains("`undef p_WIDTH"))
+            .stdout(predicate::str::contains("module p_macros"));
+
+        // Each bundle renames its macros with its own prefix.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("-f")
+            .arg("test/bundles_macros.json")
+            .arg("--keep_defines")
+            .arg("--rename_defines");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("`define a_WIDTH 8"))
+            .stdout(predicate::str::contains("`undef a_WIDTH"))
+            .stdout(predicate::str::contains("`define b_WIDTH 16"));
+
+        // With --unifdef, the kept usages and conditionals are renamed as well.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/macros.sv")
+            .args(["--unifdef", "-D", "FOO", "--rename_defines", "-p", "p_"]);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("`define p_WIDTH 8"))
+            .stdout(predicate::str::contains("`ifndef p_SYNTHESIS"))
+            .stdout(predicate::str::contains("input  logic [`p_DWIDTH-1:0] d,"))
+            .stdout(predicate::str::contains("`undef p_WIDTH"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unit_scope() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/unit_scope.sv").arg("-p").arg("p_");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("typedef logic [7:0] p_byte_t;"))
+            .stdout(predicate::str::contains(
+                "localparam int unsigned p_Depth = 4;",
+            ))
+            .stdout(predicate::str::contains(
+                "function automatic p_byte_t p_incr(p_byte_t x);",
+            ))
+            .stdout(predicate::str::contains("input  p_byte_t [p_Depth-1:0] d,"))
+            .stdout(predicate::str::contains("assign q[i] = p_incr(d[i]);"))
+            .stderr(predicate::str::contains("typedef `byte_t`"))
+            .stderr(predicate::str::contains("function `incr`"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unit_scope_shadow() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/unit_scope_shadow.sv").arg("-p").arg("p_");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("localparam int p_W = 8;"))
+            // The parameter of the submodule shadows the compilation-unit item.
+            .stdout(predicate::str::contains("parameter int W = 2"))
+            .stdout(predicate::str::contains("output logic [W-1:0] q"))
+            .stdout(predicate::str::contains("p_unit_scope_sub #(.W(4)) i_sub"))
+            .stdout(predicate::str::contains("assign q[p_W-1:4] = '0;"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bind() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/bind.sv").arg("-p").arg("p_");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "defparam p_bind_tb.i_dut.Width = 2;",
+            ))
+            .stdout(predicate::str::contains("$root.p_bind_tb.i_dut.sig"))
+            .stdout(predicate::str::contains("bind p_bind_dut p_bind_chk i_chk"));
+
+        // The bound checker is kept along with the unit it is bound into.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/bind.sv").arg("--top").arg("bind_dut");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module bind_chk"))
+            .stdout(predicate::str::contains("module bind_tb").not());
+
+        // So is a checker bound within the body of a unit.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/bind_body.sv")
+            .arg("--top")
+            .arg("bind_body_dut");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module bind_body_chk"))
+            .stdout(predicate::str::contains("module bind_body_tb").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_localize_imports() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/global_import.sv")
+            .arg("-p")
+            .arg("p_")
+            .arg("--localize_imports");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("\nimport").not())
+            .stdout(predicate::str::contains(
+                "module p_global_import import p_global_pkg::*; (",
+            ))
+            .stdout(predicate::str::contains(
+                "package p_global_user_pkg;\n  import p_global_pkg::*;",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_isolate_directives() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/directives.sv")
+            .arg("test/directives_2.sv")
+            .arg("--isolate_directives");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "endmodule\n`resetall\n`celldefine",
+            ))
+            .stdout(predicate::str::ends_with("endmodule\n`endcelldefine\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_timeunits() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/timescale.sv")
+            .arg("test/timescale_2.sv")
+            .arg("--normalize_timeunits");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("`timescale 1ns/100fs\n"))
+            .stdout(predicate::str::contains("1ps").not())
+            .stdout(predicate::str::contains("timeunit").not())
+            .stderr(predicate::str::contains(
+                "Conflicting time units and precisions",
+            ));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/timescale.sv")
+            .arg("--normalize_timeunits=10ns/1ns");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("`timescale 10ns/1ns\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_translate_off() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/translate_off.sv")
+            .args(["-I", "test"])
+            .arg("--strip_translate_off")
+            .arg("--strip-comments");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("$display").not())
+            .stdout(predicate::str::contains("translate").not())
+            .stdout(predicate::str::contains("logic synthesized;"))
+            .stderr(predicate::str::contains("translate_off.sv:5-9"))
+            .stderr(predicate::str::contains(
+                "Repeated `/* pragma translate_off */` in test/translate_off.sv:7",
+            ))
+            .stderr(predicate::str::contains(
+                "Unmatched `// synopsys translate_on` in test/translate_off.sv:11",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_assertions() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/assertions.sv").arg("--strip_assertions");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("property").not())
+            .stdout(predicate::str::contains("cg_a").not())
+            .stdout(predicate::str::contains("assert (a || b);"))
+            .stderr(predicate::str::contains(
+                "Stripped from `assertions` in test/assertions.sv: 1 assert, 1 cover, 1 covergroup, \
+                 1 covergroup instance, 1 covergroup use, 1 property",
+            ));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/assertions.sv")
+            .arg("--strip_assertions")
+            .arg("--strip_immediate_assertions");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("assert (").not())
+            .stdout(predicate::str::contains("always_ff @(posedge clk) begin"));
+
+        // Removed items take their lines along.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/assertions_trailing.sv")
+            .arg("--strip_assertions");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("  assign q = a;\nendmodule"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unifdef() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/unifdef.sv")
+            .args([
+                "--unifdef",
+                "-I",
+                "test",
+                "-D",
+                "TARGET_ASIC",
+                "-D",
+                "WIDTH=4",
+            ])
+            .args(["-p", "p_"]);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("`ifdef TARGET_ASIC").not())
+            .stdout(predicate::str::contains("i_fpga").not())
+            .stdout(predicate::str::contains("`define FF"))
+            .stdout(predicate::str::contains("`FF(q, '0, '0)"))
+            // The given macros are resolved within kept definitions as well.
+            .stdout(predicate::str::contains("`define DW (4*2)"))
+            .stdout(predicate::str::contains("`define SEL \\\n  1'b1\n"))
+            .stdout(predicate::str::contains("logic [`DW-1:0] w;"))
+            .stdout(predicate::str::contains(
+                "`ifdef VERILATOR\n  p_unifdef_leaf #(.Width(4)) i_verilator (.*);\n`else\n  \
+                 p_unifdef_leaf #(.Width(2)) i_asic_2 (.*);\n`endif",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_includes() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/keep_includes.sv")
+            .arg("test/preprocess.sv")
+            .args(["-I", "test", "--keep_includes"])
+            .arg(dir.path());
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("`include \"include.svh\"").count(2))
+            .stdout(predicate::str::contains("`define FF").not())
+            .stdout(predicate::str::contains(
+                "always_ff @(posedge clk_i or negedge rst_ni)",
+            ));
+
+        let header = std::fs::read_to_string(dir.path().join("include.svh"))?;
+        assert_eq!(header, std::fs::read_to_string("test/include.svh")?);
+
+        // Compilation-unit scope items declared in a header are renamed in the header as well.
+        let dir = assert_fs::TempDir::new()?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/unit_header.sv")
+            .args(["-I", "test", "-p", "p_", "--keep_includes"])
+            .arg(dir.path());
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("input  p_byte_t d,"));
+
+        let header = std::fs::read_to_string(dir.path().join("unit_header.svh"))?;
+        assert!(header.contains("typedef logic [7:0] p_byte_t;"));
+        assert!(header.contains("`define UNIT_HEADER_SVH_"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_map() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/preprocess.sv").arg("test/modules.sv").args([
+            "-I",
+            "test",
+            "--line_directives",
+        ]);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "`line 3 \"test/preprocess.sv\" 0\nmodule test;",
+            ))
+            .stdout(predicate::str::contains(
+                "`line 9 \"test/preprocess.sv\" 0\nendmodule",
+            ))
+            .stdout(predicate::str::contains(
+                "`line 1 \"test/modules.sv\" 0\nmodule module_1;",
+            ));
+
+        let source_map = assert_fs::NamedTempFile::new("source_map.json")?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--exclude")
+            .arg("module_1")
+            .arg("--source_map")
+            .arg(source_map.path());
+        cmd.assert().success();
+
+        // The header takes two lines, the excluded module five.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("resolve").arg(source_map.path()).arg("4");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::diff("test/modules.sv:7\n"));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("resolve").arg(source_map.path()).arg("1");
+        cmd.assert().failure();
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_output() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("test/package.sv")
+            .args(["-p", "p_", "--split_output"])
+            .arg(dir.path());
+
+        let modules = dir.path().join("modules.sv");
+        let package = dir.path().join("package.sv");
+        cmd.assert().success().stdout(predicate::str::diff(format!(
+            "{}\n{}\n",
+            modules.display(),
+            package.display()
+        )));
+        assert!(std::fs::read_to_string(modules)?.contains("module p_module_4"));
+
+        let dir = assert_fs::TempDir::new()?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .args(["-p", "p_", "--split_by", "unit", "--split_output"])
+            .arg(dir.path());
+
+        // Dependencies come first.
+        let files = ["p_module_2", "p_module_1", "p_module_3", "p_module_4"]
+            .map(|unit| format!("{}\n", dir.path().join(format!("{}.sv", unit)).display()));
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::diff(files.concat()));
+        let module_1 = std::fs::read_to_string(dir.path().join("p_module_1.sv"))?;
+        assert!(module_1.contains("module p_module_1;\n    p_module_2 i_module_2();"));
+        assert!(!module_1.contains("module p_module_2"));
+
+        // Units are ordered even if the files they are declared in depend on each other.
+        let dir = assert_fs::TempDir::new()?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/split_cycle_1.sv")
+            .arg("test/split_cycle_2.sv")
+            .args(["--split_by", "unit", "--split_output"])
+            .arg(dir.path());
+
+        let files = ["split_cycle_a_pkg", "split_cycle_b_pkg", "split_cycle_top"]
+            .map(|unit| format!("{}\n", dir.path().join(format!("{}.sv", unit)).display()));
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::diff(files.concat()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_blackbox() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/blackbox.sv")
+            .args(["--blackbox", "sram_*", "--top", "top"]);
+
+        // Only the parameters and ports are kept, and the instances of a black box are pruned.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "  output sram_pkg::word_t    rdata_o\n);\n  localparam int unsigned AddrWidth = $clog2(Depth);\nendmodule",
+            ))
+            .stdout(predicate::str::contains(
+                "  input clk_i;\n  output [Width-1:0] rdata_o;\nendmodule",
+            ))
+            .stdout(predicate::str::contains("sram_cell").not())
+            .stdout(predicate::str::contains("package sram_pkg"))
+            .stderr(predicate::str::contains(
+                "Replacing the body of `sram_macro` in test/blackbox.sv with a black box",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_top_wrapper() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/top_wrapper.sv")
+            .args(["-p", "vendor_", "--top", "top", "--top_wrapper"]);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module vendor_top #("))
+            .stdout(predicate::str::ends_with(
+                "module top #(
+  parameter int unsigned Width = 8,
+  parameter type         data_t = vendor_top_pkg::data_t,
+  localparam int unsigned Depth = 2 * Width
+) (
+  input  logic           clk_i,
+  input  vendor_top_pkg::data_t data_i,
+  output data_t          data_o
+);
+  vendor_top #(
+    .Width(Width),
+    .data_t(data_t)
+  ) i_vendor_top (
+    .clk_i(clk_i),
+    .data_i(data_i),
+    .data_o(data_o)
+  );
+endmodule
+",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_param() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/top_wrapper.sv").args([
+            "-p",
+            "p_",
+            "--param",
+            "top.Width=16",
+            "--param",
+            "top.data_t=logic [15:0]",
+        ]);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "  parameter int unsigned Width = 16,\n  parameter type         data_t = logic [15:0],\n",
+            ));
+
+        // Local parameters cannot be overridden.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/top_wrapper.sv")
+            .args(["--param", "top.Depth=4"]);
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "`Depth` is a localparam of `top` and cannot be overridden",
+        ));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/top_wrapper.sv")
+            .args(["--param", "top.Size=4"]);
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Module `top` has no parameter `Size`, found: Width, data_t",
+        ));
+
+        // A parameter can only be overridden once.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/top_wrapper.sv")
+            .args(["--param", "top.Width=16", "--param", "top.Width=32"]);
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Parameter `top.Width` is overridden more than once",
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_substitute() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/substitute.sv").args([
+            "-p",
+            "p_",
+            "--top",
+            "top",
+            "--substitute",
+            "sram_model=test/substitute_fpga.sv",
+        ]);
+
+        // The replacement is renamed, and its dependencies are kept.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("logic [7:0] mem;").not())
+            .stdout(predicate::str::contains("module p_sram_model #(").count(1))
+            .stdout(predicate::str::contains("endmodule : p_sram_model"))
+            .stdout(predicate::str::contains("module p_xilinx_bram #("));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/substitute.sv")
+            .args(["--substitute", "top=test/substitute_fpga.sv"]);
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Ports or parameters of the substitute of `top` in test/substitute_fpga.sv do not \
+             match the ones in test/substitute.sv:\n\tadditional input `clk_i`",
+        ));
+
+        // Data types and parameters have to match as well.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/substitute.sv")
+            .args(["--substitute", "sram_model=test/substitute_wide.sv"]);
+
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "output `rdata_o` is `logic[15:0]` instead of `logic[7:0]`",
+            ))
+            .stderr(predicate::str::contains("missing parameter `Depth`"));
+
+        Ok(())
+    }
+
     #[test]
     fn test_import() -> Result<()> {
         let mut cmd = Command::cargo_bin("morty")?;